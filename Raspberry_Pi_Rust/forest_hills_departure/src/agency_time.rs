@@ -0,0 +1,60 @@
+extern crate chrono;
+extern crate chrono_tz;
+extern crate serde;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+/// Time zone of the MBTA, used unless another agency's time zone is configured
+pub const DEFAULT_TIME_ZONE: Tz = chrono_tz::America::New_York;
+
+/// The current time in the agency's time zone, whatever time zone the system is set to
+pub fn now(time_zone: Tz) -> DateTime<Tz> {
+    Utc::now().with_timezone(&time_zone)
+}
+
+/// Writes a time as RFC 3339 followed by the name of its time zone, e.g.
+/// "2021-06-14T08:17:00-04:00 America/New_York".  For #[serde(with = "crate::agency_time")]
+pub fn serialize<S: Serializer>(time: &DateTime<Tz>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{} {}", time.to_rfc3339(), time.timezone().name()))
+}
+
+/// Reads a time written by serialize back into its time zone
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Tz>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    let (time, time_zone) = text
+        .split_once(' ')
+        .ok_or_else(|| D::Error::custom(format!("time without a time zone: {}", text)))?;
+    let time = DateTime::parse_from_rfc3339(time).map_err(D::Error::custom)?;
+    let time_zone: Tz = time_zone.parse().map_err(D::Error::custom)?;
+    Ok(time.with_timezone(&time_zone))
+}
+
+/// The same for optional times, for #[serde(default, with = "crate::agency_time::optional")]
+pub mod optional {
+    use chrono::DateTime;
+    use chrono_tz::Tz;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    /// Writes a time like agency_time::serialize, or null if there is none
+    pub fn serialize<S: Serializer>(
+        time: &Option<DateTime<Tz>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => super::serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    /// Reads a time written by serialize, None for null
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Tz>>, D::Error> {
+        #[derive(Deserialize)]
+        struct Time(#[serde(with = "super")] DateTime<Tz>);
+        let time = Option::<Time>::deserialize(deserializer)?;
+        Ok(time.map(|Time(time)| time))
+    }
+}
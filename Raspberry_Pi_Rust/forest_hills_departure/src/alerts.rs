@@ -0,0 +1,45 @@
+extern crate chrono;
+extern crate std;
+
+use crate::mbta_api::{Alert, MbtaClient};
+use chrono::Utc;
+use std::cmp::Reverse;
+
+/// Lowest severity, on the MBTA's 0 to 10 scale, shown unless another is configured
+pub const DEFAULT_MIN_SEVERITY: u8 = 5;
+
+/// Retrieves the alerts currently in effect for the station and routes that are at least
+/// min_severity, most severe first
+pub fn service_alerts(
+    client: &MbtaClient,
+    station: &str,
+    routes: &[String],
+    min_severity: u8,
+) -> Result<Vec<Alert>, Box<dyn std::error::Error>> {
+    // MBTA API for alerts affecting a stop
+    let path = format!(
+        "/alerts?filter[stop]={}&filter[route]={}",
+        station,
+        routes.join(",")
+    );
+    let now = Utc::now();
+    let mut alerts: Vec<Alert> = client
+        .get::<Alert>(&path)?
+        .data
+        .into_iter()
+        .map(|alert| alert.attributes)
+        .filter(|alert| alert.severity >= min_severity && alert.is_active(now))
+        .collect();
+    alerts.sort_by_key(|alert| Reverse(alert.severity));
+    Ok(alerts)
+}
+
+/// Text of an alert that fits a banner, the short header if the MBTA gave one
+pub fn banner_text(alert: &Alert) -> String {
+    alert
+        .short_header
+        .as_deref()
+        .filter(|short_header| !short_header.is_empty())
+        .unwrap_or(&alert.header)
+        .to_string()
+}
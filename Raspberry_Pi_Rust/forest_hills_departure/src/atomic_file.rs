@@ -0,0 +1,21 @@
+extern crate std;
+
+use std::{fs, path::Path};
+
+/// Writes the contents to a file, replacing it in one step by writing a .partial file next to it
+/// first.  A failed write or a power cut while writing leaves the previous file intact.  What is
+/// written, e.g. "cache", is named in the error
+pub fn write(path: &Path, contents: &[u8], what: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let partial_path = path.with_extension("partial");
+    fs::write(&partial_path, contents).map_err(|err| {
+        format!(
+            "could not write {} {}: {}",
+            what,
+            partial_path.display(),
+            err
+        )
+    })?;
+    fs::rename(&partial_path, path)
+        .map_err(|err| format!("could not write {} {}: {}", what, path.display(), err))?;
+    Ok(())
+}
@@ -0,0 +1,138 @@
+extern crate chrono_tz;
+extern crate serde;
+extern crate std;
+extern crate toml;
+
+use crate::agency_time::DEFAULT_TIME_ZONE;
+use crate::departure_cache::DEFAULT_CACHE_PATH;
+use crate::station_catalog::DEFAULT_STATIONS_PATH;
+use crate::mbta_api::{MbtaClient, DEFAULT_API_URL};
+use crate::train_time::TimeEvent;
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::{fmt, fs, path::Path, path::PathBuf, str::FromStr};
+
+/// Which backend departures are retrieved from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// The live MBTA V3 API
+    #[default]
+    Mbta,
+    /// Recorded MBTA API responses on disk
+    Fixture,
+    /// A GTFS static timetable with GTFS-Realtime TripUpdates, from any agency
+    Gtfs,
+}
+
+impl FromStr for SourceKind {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        match source {
+            "mbta" => Ok(SourceKind::Mbta),
+            "fixture" => Ok(SourceKind::Fixture),
+            "gtfs" => Ok(SourceKind::Gtfs),
+            _ => Err(format!("unknown departure source: {}", source)),
+        }
+    }
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceKind::Mbta => write!(f, "mbta"),
+            SourceKind::Fixture => write!(f, "fixture"),
+            SourceKind::Gtfs => write!(f, "gtfs"),
+        }
+    }
+}
+
+/// Settings from the TOML configuration file.  Anything also given on the command line is
+/// overridden by the command line value
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub source: SourceKind,
+    // count down to the trains' departures, or to their arrivals
+    pub event: TimeEvent,
+    // tz database name of the agency's time zone, e.g. "America/Chicago", for the train times and
+    // service days whatever the system's time zone is.  agency_time::DEFAULT_TIME_ZONE if None
+    pub time_zone: Option<Tz>,
+    // base url of the MBTA V3 API, https://api-v3.mbta.com if None
+    pub api_url: Option<String>,
+    // MBTA V3 API key, requests are anonymous if None
+    pub api_key: Option<String>,
+    // stream prediction changes instead of polling every minute
+    pub stream: bool,
+    // lowest alert severity shown, 0 to 10, alerts::DEFAULT_MIN_SEVERITY if None
+    pub alert_severity: Option<u8>,
+    // do not fetch or show service alerts
+    pub hide_alerts: bool,
+    // look up the next trains' vehicles, for where the next train is and how full they are, which
+    // takes two more requests every refresh
+    pub show_vehicle: bool,
+    // GTFS static zip with the timetable, used by the gtfs source and when the MBTA API can not be
+    // reached
+    pub gtfs_path: Option<PathBuf>,
+    // url or file of the GTFS-Realtime TripUpdates feed for the gtfs source
+    pub trip_updates: Option<String>,
+    // directory holding predictions.json and schedules.json for the fixture source
+    pub fixture_dir: Option<PathBuf>,
+    // file the last departures are kept in across restarts,
+    // departure_cache::DEFAULT_CACHE_PATH if None
+    pub cache_path: Option<PathBuf>,
+    // minutes after being retrieved that cached departures are still shown after a restart,
+    // departure_cache::DEFAULT_MAX_AGE_MIN if None
+    pub cache_max_age_min: Option<i64>,
+    // do not keep the departures on disk
+    pub no_cache: bool,
+    // file the refresh-stations command keeps the station catalog in,
+    // station_catalog::DEFAULT_STATIONS_PATH if None
+    pub stations_path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file
+    pub fn load(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("could not read config {}: {}", path.display(), err))?;
+        let config = toml::from_str(&text)
+            .map_err(|err| format!("invalid config {}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    /// The file departures are cached in, None if caching is turned off
+    pub fn cache_path(&self) -> Option<PathBuf> {
+        if self.no_cache {
+            return None;
+        }
+        Some(
+            self.cache_path
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_PATH)),
+        )
+    }
+
+    /// The agency's time zone
+    pub fn time_zone(&self) -> Tz {
+        self.time_zone.unwrap_or(DEFAULT_TIME_ZONE)
+    }
+
+    /// The file the station catalog is refreshed into and read from
+    pub fn stations_path(&self) -> PathBuf {
+        self.stations_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STATIONS_PATH))
+    }
+
+    /// Creates a client for the configured MBTA API url, key and time zone
+    pub fn mbta_client(&self) -> MbtaClient {
+        let mut client = MbtaClient::new(self.api_url.as_deref().unwrap_or(DEFAULT_API_URL))
+            .with_time_zone(self.time_zone());
+        if let Some(api_key) = &self.api_key {
+            client = client.with_api_key(api_key);
+        }
+        client
+    }
+}
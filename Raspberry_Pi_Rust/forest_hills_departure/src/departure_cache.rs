@@ -0,0 +1,69 @@
+extern crate chrono;
+extern crate chrono_tz;
+extern crate serde;
+extern crate serde_json;
+extern crate std;
+
+use crate::agency_time;
+use crate::atomic_file;
+use crate::train_time::{Departure, DepartureQuery};
+use chrono::{DateTime, Duration};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// File the departures are cached in unless another is configured.  /var/tmp survives reboots,
+/// unlike /tmp
+pub const DEFAULT_CACHE_PATH: &str = "/var/tmp/forest_hills_departure.json";
+
+/// Minutes a cached departure set is used for after it was retrieved, unless another age is
+/// configured
+pub const DEFAULT_MAX_AGE_MIN: i64 = 120;
+
+/// The last departures retrieved, kept on disk so they can be shown straight after a restart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedDepartures {
+    // what the departures were retrieved for, as every query shares the default cache file
+    pub query: DepartureQuery,
+    #[serde(with = "agency_time")]
+    pub fetched_at: DateTime<Tz>,
+    pub departures: Option<Vec<Departure>>,
+}
+
+impl CachedDepartures {
+    /// Writes the departures to the cache file, leaving the previous cache intact if the write fails
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        atomic_file::write(path, &serde_json::to_vec(self)?, "cache")
+    }
+
+    /// Reads the departures retrieved for the query from the cache file, without the trains that
+    /// left before now.  Returns None if there is no cache, it is for another query or it was
+    /// retrieved more than max_age before now
+    pub fn load(
+        path: &Path,
+        query: &DepartureQuery,
+        max_age: Duration,
+        now: DateTime<Tz>,
+    ) -> Result<Option<CachedDepartures>, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("could not read cache {}: {}", path.display(), err))?;
+        let mut cached: CachedDepartures = serde_json::from_str(&text)
+            .map_err(|err| format!("malformed cache {}: {}", path.display(), err))?;
+        if cached.query != *query || now.signed_duration_since(cached.fetched_at) > max_age {
+            return Ok(None);
+        }
+        cached.departures = cached
+            .departures
+            .map(|departures| {
+                departures
+                    .into_iter()
+                    .filter(|departure| departure.time > now)
+                    .collect::<Vec<Departure>>()
+            })
+            .filter(|departures| !departures.is_empty());
+        Ok(Some(cached))
+    }
+}
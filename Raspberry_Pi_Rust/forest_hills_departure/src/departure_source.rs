@@ -0,0 +1,406 @@
+extern crate chrono;
+extern crate chrono_tz;
+extern crate serde;
+extern crate std;
+
+use crate::agency_time;
+use crate::config::{Config, SourceKind};
+use crate::gtfs_realtime;
+use crate::gtfs_static::GtfsFeed;
+use crate::mbta_api::{Document, MbtaClient, Prediction, ResourceType, Route, Schedule};
+use crate::train_time::{
+    self, Departure, DepartureQuery, DestinationSchedules, Direction, EndpointHealth,
+    MergedDepartures, Predictions, StreamUpdate, TripDepartures, VehicleStatuses,
+};
+use chrono::{DateTime, Duration};
+use chrono_tz::Tz;
+use log::warn;
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+/// A backend that departures can be retrieved from.  Implemented for the MBTA V3 API, for
+/// recorded API responses and for GTFS feeds, other feed formats only need another implementation
+pub trait DepartureSource: Send {
+    /// Retrieves the upcoming departures sorted by time, or None if there are none, with how the
+    /// predictions and schedules they are merged from were retrieved
+    fn departures(
+        &mut self,
+        query: &DepartureQuery,
+    ) -> Result<MergedDepartures, Box<dyn std::error::Error>>;
+
+    /// Retrieves the ids of all routes that serve the station
+    fn station_routes(&mut self, station: &str) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+
+    /// Retrieves the directions of a route with their names and destinations.  Sources without
+    /// route metadata return an error, so the caller can fall back to direction ids
+    fn route_directions(
+        &mut self,
+        _route: &str,
+    ) -> Result<Vec<Direction>, Box<dyn std::error::Error>> {
+        Err("this departure source has no direction names".into())
+    }
+
+    /// Keeps the departures current by streaming changes, calling on_update with the departures
+    /// after every change and with Alive whenever the stream shows it is still open otherwise.
+    /// Returns when the stream closes.  Sources that can not stream return an error straight
+    /// away, so the caller can poll departures instead
+    fn stream(
+        &mut self,
+        _query: &DepartureQuery,
+        _on_update: &mut dyn FnMut(StreamUpdate),
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("this departure source can not stream departures".into())
+    }
+}
+
+/// Seconds between vehicle lookups while streaming predictions
+const VEHICLE_REFRESH_SECS: i64 = 30;
+
+/// Creates the departure source chosen in the configuration for the station
+pub fn from_config(
+    config: &Config,
+    station: &str,
+) -> Result<Box<dyn DepartureSource>, Box<dyn std::error::Error>> {
+    match config.source {
+        SourceKind::Mbta => {
+            let mut source = MbtaSource::new(config.mbta_client());
+            if let Some(gtfs_path) = &config.gtfs_path {
+                source = source.with_timetable(GtfsFeed::load(gtfs_path, station)?);
+            }
+            if config.show_vehicle {
+                source = source.with_vehicles();
+            }
+            Ok(Box::new(source))
+        }
+        SourceKind::Fixture => {
+            let fixture_dir = config
+                .fixture_dir
+                .as_ref()
+                .ok_or("the fixture source needs a fixture directory")?;
+            Ok(Box::new(FixtureSource::load(fixture_dir)?))
+        }
+        SourceKind::Gtfs => {
+            let gtfs_path = config
+                .gtfs_path
+                .as_ref()
+                .ok_or("the gtfs source needs a GTFS static zip")?;
+            let trip_updates = config
+                .trip_updates
+                .as_ref()
+                .ok_or("the gtfs source needs a GTFS-Realtime TripUpdates url or file")?;
+            Ok(Box::new(GtfsRealtimeSource::new(
+                GtfsFeed::load(gtfs_path, station)?,
+                trip_updates,
+            )))
+        }
+    }
+}
+
+/// Departures from the live MBTA V3 API, falling back to a GTFS timetable if the API can not be
+/// reached
+#[derive(Debug, Default)]
+pub struct MbtaSource {
+    client: MbtaClient,
+    timetable: Option<GtfsFeed>,
+    // look up where the next trains are
+    vehicles: bool,
+    // the trips calling at the filter's destination, kept for the service day
+    destination_schedules: DestinationSchedules,
+}
+
+impl MbtaSource {
+    pub fn new(client: MbtaClient) -> MbtaSource {
+        MbtaSource {
+            client,
+            timetable: None,
+            vehicles: false,
+            destination_schedules: DestinationSchedules::default(),
+        }
+    }
+
+    /// Uses the scheduled departures from the timetable whenever train times can not be
+    /// retrieved from the API
+    pub fn with_timetable(mut self, timetable: GtfsFeed) -> MbtaSource {
+        self.timetable = Some(timetable);
+        self
+    }
+
+    /// Adds where the trains are to the next predicted departures, which takes two more
+    /// requests every refresh
+    pub fn with_vehicles(mut self) -> MbtaSource {
+        self.vehicles = true;
+        self
+    }
+}
+
+/// Where the trains of the departures are, none if vehicles are not looked up or can not be
+/// retrieved
+fn find_vehicles(
+    client: &MbtaClient,
+    vehicles: bool,
+    departures: &Option<Vec<Departure>>,
+) -> VehicleStatuses {
+    if !vehicles {
+        return VehicleStatuses::new();
+    }
+    let departures = departures.as_deref().unwrap_or_default();
+    train_time::get_vehicle_statuses(client, departures).unwrap_or_else(|err| {
+        warn!("could not retrieve where the trains are: {}", err);
+        VehicleStatuses::new()
+    })
+}
+
+impl DepartureSource for MbtaSource {
+    fn departures(
+        &mut self,
+        query: &DepartureQuery,
+    ) -> Result<MergedDepartures, Box<dyn std::error::Error>> {
+        let mut merged = train_time::train_times(
+            &self.client,
+            query,
+            self.timetable.as_ref(),
+            &mut self.destination_schedules,
+        )?;
+        if let EndpointHealth::Failed(err) = &merged.predictions {
+            warn!("could not retrieve predictions, showing scheduled times only: {}", err);
+        }
+        if let EndpointHealth::Failed(err) = &merged.schedules {
+            warn!("could not retrieve schedules, showing predicted times only: {}", err);
+        }
+        let vehicle_statuses = find_vehicles(&self.client, self.vehicles, &merged.departures);
+        merged.departures = train_time::add_vehicles(merged.departures, &vehicle_statuses);
+        Ok(merged)
+    }
+
+    fn station_routes(&mut self, station: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        train_time::station_routes(&self.client, station)
+    }
+
+    fn route_directions(
+        &mut self,
+        route: &str,
+    ) -> Result<Vec<Direction>, Box<dyn std::error::Error>> {
+        train_time::get_route_directions(&self.client, route)
+    }
+
+    fn stream(
+        &mut self,
+        query: &DepartureQuery,
+        on_update: &mut dyn FnMut(StreamUpdate),
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (client, vehicles) = (&self.client, self.vehicles);
+        let destination_schedules = &mut self.destination_schedules;
+        let mut destination_stops = || {
+            train_time::destination_stops(
+                client,
+                &query.filter,
+                &query.dir_code,
+                &query.routes,
+                query.time_zone,
+                destination_schedules,
+            )
+        };
+        // the arrivals at the destination are refreshed along with the schedules, the stream only
+        // covers the station
+        let mut stops = destination_stops();
+        let mut stops_at = agency_time::now(query.time_zone);
+        // vehicles move on between prediction changes, so they are looked up on their own timer
+        let mut vehicle_statuses = VehicleStatuses::new();
+        let mut vehicles_at: Option<DateTime<Tz>> = None;
+        train_time::stream_train_times(
+            client,
+            &query.dir_code,
+            &query.station,
+            &query.routes,
+            query.event,
+            query.time_zone,
+            &mut |update| {
+                let departures = match update {
+                    StreamUpdate::Departures(departures) => departures,
+                    StreamUpdate::Alive => return on_update(StreamUpdate::Alive),
+                };
+                let now = agency_time::now(query.time_zone);
+                if now.signed_duration_since(stops_at)
+                    > Duration::minutes(train_time::SCHEDULE_REFRESH_MIN)
+                {
+                    // keep the old trips if they can not be retrieved
+                    stops = destination_stops().or_else(|| stops.take());
+                    stops_at = now;
+                }
+                let departures = query.filter.apply(departures, stops.as_ref());
+                let vehicles_due = vehicles_at.is_none_or(|vehicles_at| {
+                    now.signed_duration_since(vehicles_at) > Duration::seconds(VEHICLE_REFRESH_SECS)
+                });
+                if vehicles_due {
+                    vehicle_statuses = find_vehicles(client, vehicles, &departures);
+                    vehicles_at = Some(now);
+                }
+                on_update(StreamUpdate::Departures(train_time::add_vehicles(
+                    departures,
+                    &vehicle_statuses,
+                )))
+            },
+        )
+    }
+}
+
+/// Departures from recorded MBTA API responses, for running without a network.  The fixture
+/// directory holds the predictions response in predictions.json and the schedules response in
+/// schedules.json
+#[derive(Debug)]
+pub struct FixtureSource {
+    predictions: Document<Prediction>,
+    schedules: Document<Schedule>,
+    // time used to filter out past trains, the current time if None
+    now: Option<DateTime<Tz>>,
+}
+
+impl FixtureSource {
+    /// Loads the recorded responses from the fixture directory
+    pub fn load(fixture_dir: &Path) -> Result<FixtureSource, Box<dyn std::error::Error>> {
+        Ok(FixtureSource {
+            predictions: load_document(&fixture_dir.join("predictions.json"))?,
+            schedules: load_document(&fixture_dir.join("schedules.json"))?,
+            now: None,
+        })
+    }
+
+    /// Uses a fixed time instead of the current time to filter out past trains
+    pub fn with_now(mut self, now: DateTime<Tz>) -> FixtureSource {
+        self.now = Some(now);
+        self
+    }
+}
+
+impl DepartureSource for FixtureSource {
+    fn departures(
+        &mut self,
+        query: &DepartureQuery,
+    ) -> Result<MergedDepartures, Box<dyn std::error::Error>> {
+        let predicted =
+            train_time::prediction_departures(&self.predictions, query.event, query.time_zone)?;
+        let scheduled =
+            train_time::schedule_departures(&self.schedules, query.event, query.time_zone)?;
+        let departures = train_time::merge_departures(
+            matching_predictions(predicted, query),
+            matching(scheduled, query),
+            self.now.unwrap_or_else(|| agency_time::now(query.time_zone)),
+        );
+        Ok(MergedDepartures::healthy(query.filter.apply(departures, None)))
+    }
+
+    fn station_routes(&mut self, _station: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        // the recordings are already for a single station, so every route in them serves it
+        let mut routes: Vec<String> = self
+            .predictions
+            .data
+            .iter()
+            .filter_map(|prediction| prediction.related_id("route"))
+            .chain(
+                self.schedules
+                    .data
+                    .iter()
+                    .filter_map(|schedule| schedule.related_id("route")),
+            )
+            .map(|route| route.to_string())
+            .collect();
+        routes.sort();
+        routes.dedup();
+        Ok(routes)
+    }
+
+    fn route_directions(
+        &mut self,
+        route: &str,
+    ) -> Result<Vec<Direction>, Box<dyn std::error::Error>> {
+        // the schedules recording includes the route resources
+        let included = self
+            .schedules
+            .included
+            .iter()
+            .find(|included| included.kind == Route::TYPE && included.id == route)
+            .ok_or_else(|| format!("route {} is not in the recording", route))?;
+        Ok(train_time::route_directions(&Route::deserialize(&included.attributes)?))
+    }
+}
+
+/// Departures from a GTFS static timetable, updated by a GTFS-Realtime TripUpdates feed.  Works
+/// for any agency publishing both feeds
+#[derive(Debug)]
+pub struct GtfsRealtimeSource {
+    timetable: GtfsFeed,
+    // url or file path of the TripUpdates feed
+    trip_updates: String,
+}
+
+impl GtfsRealtimeSource {
+    pub fn new(timetable: GtfsFeed, trip_updates: &str) -> GtfsRealtimeSource {
+        GtfsRealtimeSource {
+            timetable,
+            trip_updates: trip_updates.to_string(),
+        }
+    }
+}
+
+impl DepartureSource for GtfsRealtimeSource {
+    fn departures(
+        &mut self,
+        query: &DepartureQuery,
+    ) -> Result<MergedDepartures, Box<dyn std::error::Error>> {
+        let now = agency_time::now(query.time_zone);
+        let scheduled = train_time::timetable_departures(&self.timetable, query, now)?;
+        let mut predictions = EndpointHealth::Ok;
+        let predicted =
+            match gtfs_realtime::load_trip_updates(
+                &self.trip_updates,
+                &self.timetable,
+                query.event,
+                now,
+            ) {
+                Ok(predicted) => predicted,
+                Err(err) => {
+                    warn!("could not retrieve trip updates, using the GTFS timetable: {}", err);
+                    predictions = EndpointHealth::Failed(err.to_string());
+                    Predictions::default()
+                }
+            };
+        let departures =
+            train_time::merge_departures(matching_predictions(predicted, query), scheduled, now);
+        Ok(MergedDepartures {
+            departures: query.filter.apply(departures, None),
+            predictions,
+            schedules: EndpointHealth::Ok,
+        })
+    }
+
+    fn station_routes(&mut self, _station: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        // the timetable only holds the trips stopping at the station
+        Ok(self.timetable.routes())
+    }
+}
+
+/// Keeps only the predictions that the API would have returned for the query
+fn matching_predictions(predicted: Predictions, query: &DepartureQuery) -> Predictions {
+    Predictions {
+        departures: matching(predicted.departures, query),
+        cancelled: predicted.cancelled,
+    }
+}
+
+/// Keeps only the departures that the API would have returned for the query
+fn matching(departures: TripDepartures, query: &DepartureQuery) -> TripDepartures {
+    departures
+        .into_iter()
+        .filter(|(_, departure)| {
+            query.routes.contains(&departure.route_id)
+                && departure.direction_id.to_string() == query.dir_code
+        })
+        .collect()
+}
+
+/// Reads and parses a recorded API response
+fn load_document<A: ResourceType>(path: &Path) -> Result<Document<A>, Box<dyn std::error::Error>> {
+    let body = fs::read_to_string(path)
+        .map_err(|err| format!("could not read fixture {}: {}", path.display(), err))?;
+    Ok(Document::parse(&path.display().to_string(), &body)?)
+}
@@ -0,0 +1,318 @@
+extern crate chrono;
+extern crate chrono_tz;
+extern crate prost;
+extern crate reqwest;
+extern crate std;
+
+use crate::gtfs_static::{service_time, GtfsFeed};
+use crate::train_time::{service_date, Departure, Predictions, TimeEvent, TimeSource};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use prost::Message;
+use std::{fs, path::Path};
+
+// GTFS-Realtime messages from gtfs-realtime.proto.  Only the fields the board uses are declared,
+// prost skips everything else, including agency extensions
+
+/// A GTFS-Realtime feed
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedMessage {
+    #[prost(message, required, tag = "1")]
+    pub header: FeedHeader,
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedHeader {
+    #[prost(string, required, tag = "1")]
+    pub gtfs_realtime_version: String,
+    // seconds since the epoch the feed was created at
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedEntity {
+    #[prost(string, required, tag = "1")]
+    pub id: String,
+    #[prost(bool, optional, tag = "2")]
+    pub is_deleted: Option<bool>,
+    #[prost(message, optional, tag = "3")]
+    pub trip_update: Option<TripUpdate>,
+}
+
+/// Realtime changes to the stop times of a trip
+#[derive(Clone, PartialEq, Message)]
+pub struct TripUpdate {
+    #[prost(message, required, tag = "1")]
+    pub trip: TripDescriptor,
+    #[prost(message, repeated, tag = "2")]
+    pub stop_time_update: Vec<StopTimeUpdate>,
+    #[prost(uint64, optional, tag = "4")]
+    pub timestamp: Option<u64>,
+    // seconds the whole trip is running late, for stops without their own update
+    #[prost(int32, optional, tag = "5")]
+    pub delay: Option<i32>,
+}
+
+/// An arrival or departure, given as an absolute time or as a delay on the timetable
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeEvent {
+    #[prost(int32, optional, tag = "1")]
+    pub delay: Option<i32>,
+    // seconds since the epoch
+    #[prost(int64, optional, tag = "2")]
+    pub time: Option<i64>,
+    #[prost(int32, optional, tag = "3")]
+    pub uncertainty: Option<i32>,
+}
+
+/// The realtime arrival and departure of a trip at one stop.  Updates are sorted by stop
+/// sequence, and a delay carries on to the later stops that have no update of their own
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub stop_sequence: Option<u32>,
+    #[prost(message, optional, tag = "2")]
+    pub arrival: Option<StopTimeEvent>,
+    #[prost(message, optional, tag = "3")]
+    pub departure: Option<StopTimeEvent>,
+    #[prost(string, optional, tag = "4")]
+    pub stop_id: Option<String>,
+    #[prost(enumeration = "StopRelationship", optional, tag = "5")]
+    pub schedule_relationship: Option<i32>,
+}
+
+/// Whether a trip stops at a stop as timetabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum StopRelationship {
+    Scheduled = 0,
+    Skipped = 1,
+    NoData = 2,
+}
+
+/// The trip a trip update is for
+#[derive(Clone, PartialEq, Message)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub start_time: Option<String>,
+    // service date of the trip, YYYYMMDD
+    #[prost(string, optional, tag = "3")]
+    pub start_date: Option<String>,
+    #[prost(enumeration = "TripRelationship", optional, tag = "4")]
+    pub schedule_relationship: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+    #[prost(uint32, optional, tag = "6")]
+    pub direction_id: Option<u32>,
+}
+
+/// Whether a trip runs as timetabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum TripRelationship {
+    Scheduled = 0,
+    Added = 1,
+    Unscheduled = 2,
+    Canceled = 3,
+    Replacement = 5,
+    Duplicated = 6,
+    Deleted = 7,
+}
+
+/// Fetches a TripUpdates feed from a url, or reads it from a file if the location is not a url,
+/// and decodes the departures from the station of the timetable at the time of the event
+pub fn load_trip_updates(
+    location: &str,
+    timetable: &GtfsFeed,
+    event: TimeEvent,
+    now: DateTime<Tz>,
+) -> Result<Predictions, Box<dyn std::error::Error>> {
+    let feed = if location.starts_with("http://") || location.starts_with("https://") {
+        read_feed_url(location)?
+    } else {
+        read_feed_file(Path::new(location))?
+    };
+    Ok(trip_update_departures(&feed, timetable, event, now))
+}
+
+/// Decodes the departures from the station of the timetable at the time of the event into the
+/// predictions merged over the timetable.  Trips without a start date are taken to run on the
+/// service day of now.  Times are given in the time zone of now
+pub fn trip_update_departures(
+    feed: &FeedMessage,
+    timetable: &GtfsFeed,
+    event: TimeEvent,
+    now: DateTime<Tz>,
+) -> Predictions {
+    let mut predictions = Predictions::default();
+    let trip_updates = feed
+        .entity
+        .iter()
+        .filter(|entity| !entity.is_deleted.unwrap_or(false))
+        .filter_map(|entity| entity.trip_update.as_ref());
+    for trip_update in trip_updates {
+        let trip_id = match &trip_update.trip.trip_id {
+            Some(trip_id) => trip_id.clone(),
+            None => continue,
+        };
+        match trip_update.trip.schedule_relationship() {
+            TripRelationship::Canceled | TripRelationship::Deleted => {
+                predictions.cancelled.insert(trip_id);
+                continue;
+            }
+            _ => (),
+        }
+        let service_date = trip_update
+            .trip
+            .start_date
+            .as_deref()
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok())
+            .unwrap_or_else(|| service_date(now));
+        match station_departure(trip_update, &trip_id, timetable, service_date, event, now.timezone()) {
+            StationDeparture::Skipped => {
+                predictions.cancelled.insert(trip_id);
+            }
+            StationDeparture::Predicted(departure) => {
+                predictions.departures.insert(trip_id, *departure);
+            }
+            StationDeparture::Unknown => (),
+        }
+    }
+    predictions
+}
+
+/// Decodes a TripUpdates feed
+pub fn decode_feed(bytes: &[u8]) -> Result<FeedMessage, prost::DecodeError> {
+    FeedMessage::decode(bytes)
+}
+
+/// Reads a TripUpdates feed from a file
+pub fn read_feed_file(path: &Path) -> Result<FeedMessage, Box<dyn std::error::Error>> {
+    let bytes = fs::read(path)
+        .map_err(|err| format!("could not read trip updates {}: {}", path.display(), err))?;
+    decode_feed(&bytes)
+        .map_err(|err| format!("malformed trip updates {}: {}", path.display(), err).into())
+}
+
+/// Fetches a TripUpdates feed from a url
+pub fn read_feed_url(url: &str) -> Result<FeedMessage, Box<dyn std::error::Error>> {
+    let bytes = reqwest::blocking::get(url)?.error_for_status()?.bytes()?;
+    decode_feed(&bytes).map_err(|err| format!("malformed trip updates {}: {}", url, err).into())
+}
+
+/// What a trip update says about the trip's departure from the station
+enum StationDeparture {
+    // boxed, as a departure is far larger than the other variants
+    Predicted(Box<Departure>),
+    Skipped,
+    // the trip does not stop at the station or has no usable time for it
+    Unknown,
+}
+
+/// Works out the departure of a trip from the station.  The time comes from the update for the
+/// station if there is one, else from the delay of the last update before the station, else from
+/// the delay of the whole trip
+fn station_departure(
+    trip_update: &TripUpdate,
+    trip_id: &str,
+    timetable: &GtfsFeed,
+    service_date: NaiveDate,
+    event: TimeEvent,
+    time_zone: Tz,
+) -> StationDeparture {
+    let stop_time = timetable.station_stop_time(trip_id);
+    let stop_sequence = stop_time.map(|stop_time| stop_time.stop_sequence);
+    let scheduled = stop_time
+        .and_then(|stop_time| {
+            event.pick(
+                stop_time.arrival_time.as_deref(),
+                stop_time.departure_time.as_deref(),
+            )
+        })
+        .and_then(|time| service_time(service_date, time, time_zone));
+    let updates = &trip_update.stop_time_update;
+    let station_update = updates.iter().find(|update| {
+        update.stop_id.as_deref().is_some_and(|stop_id| timetable.serves(stop_id))
+            || (stop_sequence.is_some() && update.stop_sequence == stop_sequence)
+    });
+    let time = match station_update {
+        Some(update) => match update.schedule_relationship() {
+            StopRelationship::Skipped => return StationDeparture::Skipped,
+            StopRelationship::NoData => None,
+            StopRelationship::Scheduled => event
+                .pick(update.arrival.as_ref(), update.departure.as_ref())
+                .and_then(|stop_event| event_time(stop_event, scheduled, time_zone)),
+        },
+        None => {
+            // a delay carries on past the stops before the station, unless they have no data
+            let earlier = updates.iter().rev().find(|update| {
+                let before = match (update.stop_sequence, stop_sequence) {
+                    (Some(update_sequence), Some(sequence)) => update_sequence < sequence,
+                    _ => false,
+                };
+                before && update.schedule_relationship() != StopRelationship::Skipped
+            });
+            let delay = match earlier {
+                Some(update) if update.schedule_relationship() == StopRelationship::NoData => None,
+                Some(update) => update
+                    .departure
+                    .as_ref()
+                    .or(update.arrival.as_ref())
+                    .and_then(|stop_event| stop_event.delay),
+                None => trip_update.delay,
+            };
+            match (scheduled, delay) {
+                (Some(scheduled), Some(delay)) => Some(scheduled + Duration::seconds(delay.into())),
+                _ => None,
+            }
+        }
+    };
+    let time = match time {
+        Some(time) => time,
+        None => return StationDeparture::Unknown,
+    };
+    let trip = timetable.trip(trip_id);
+    let route_id = match (&trip_update.trip.route_id, trip) {
+        (Some(route_id), _) => route_id.clone(),
+        (None, Some(trip)) => trip.route_id.clone(),
+        (None, None) => return StationDeparture::Unknown,
+    };
+    let direction_id = trip_update
+        .trip
+        .direction_id
+        .map(|direction_id| direction_id as u8)
+        .or_else(|| trip.and_then(|trip| trip.direction_id))
+        .unwrap_or_default();
+    StationDeparture::Predicted(Box::new(Departure {
+        trip_id: trip_id.to_string(),
+        route_id,
+        headsign: trip.and_then(|trip| trip.trip_headsign.clone()),
+        direction_id,
+        time,
+        source: TimeSource::Predicted,
+        status: None,
+        stop_sequence: stop_sequence.or_else(|| station_update.and_then(|update| update.stop_sequence)),
+        cancelled: false,
+        next_service_day: false,
+        destination_time: None,
+        vehicle: None,
+    }))
+}
+
+/// The time of an arrival or departure, from its absolute time or its delay on the scheduled time
+fn event_time(
+    event: &StopTimeEvent,
+    scheduled: Option<DateTime<Tz>>,
+    time_zone: Tz,
+) -> Option<DateTime<Tz>> {
+    match (event.time, event.delay, scheduled) {
+        (Some(time), _, _) => time_zone.timestamp_opt(time, 0).single(),
+        (None, Some(delay), Some(scheduled)) => Some(scheduled + Duration::seconds(delay.into())),
+        _ => None,
+    }
+}
@@ -0,0 +1,328 @@
+extern crate chrono;
+extern crate chrono_tz;
+extern crate csv;
+extern crate serde;
+extern crate std;
+extern crate zip;
+
+use crate::train_time::{service_date, Departure, TimeEvent, TimeSource};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Weekday};
+use chrono_tz::Tz;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::{fs::File, path::Path};
+
+/// A stop, platform or parent station from stops.txt
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct GtfsStop {
+    pub stop_id: String,
+    pub stop_name: String,
+    pub parent_station: Option<String>,
+    pub location_type: Option<u8>,
+}
+
+/// A trip from trips.txt
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct GtfsTrip {
+    pub route_id: String,
+    pub service_id: String,
+    pub trip_id: String,
+    pub trip_headsign: Option<String>,
+    pub direction_id: Option<u8>,
+}
+
+/// A trip stopping at a stop, from stop_times.txt.  Times are HH:MM:SS from the start of the
+/// service day and can be past 24:00:00 for trips running after midnight
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct GtfsStopTime {
+    pub trip_id: String,
+    pub arrival_time: Option<String>,
+    pub departure_time: Option<String>,
+    pub stop_id: String,
+    pub stop_sequence: u32,
+}
+
+/// The weekdays and date range a service runs on, from calendar.txt
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct GtfsCalendar {
+    pub service_id: String,
+    pub monday: u8,
+    pub tuesday: u8,
+    pub wednesday: u8,
+    pub thursday: u8,
+    pub friday: u8,
+    pub saturday: u8,
+    pub sunday: u8,
+    pub start_date: String,
+    pub end_date: String,
+}
+
+/// A service added (1) or removed (2) on a single date, from calendar_dates.txt
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct GtfsCalendarDate {
+    pub service_id: String,
+    pub date: String,
+    pub exception_type: u8,
+}
+
+/// Timetable of a single station from a GTFS static feed, for finding scheduled departures
+/// without a network
+#[derive(Debug, Clone, Default)]
+pub struct GtfsFeed {
+    // the station and its platforms
+    stop_ids: HashSet<String>,
+    trips: HashMap<String, GtfsTrip>,
+    stop_times: Vec<GtfsStopTime>,
+    calendars: Vec<GtfsCalendar>,
+    calendar_dates: Vec<GtfsCalendarDate>,
+}
+
+impl GtfsFeed {
+    /// Loads the timetable of a station, platform or stop id from a GTFS zip, e.g.
+    /// MBTA_GTFS.zip.  Only the stop times at the station are kept, as the full feed does not fit
+    /// in a Raspberry Pi's memory
+    pub fn load(path: &Path, station: &str) -> Result<GtfsFeed, Box<dyn std::error::Error>> {
+        let file = File::open(path)
+            .map_err(|err| format!("could not open GTFS feed {}: {}", path.display(), err))?;
+        let mut archive = zip::ZipArchive::new(file)?;
+        // the stop ids of the station and all of its platforms, which have it as their parent
+        let stop_ids: HashSet<String> = read_table(&mut archive, "stops.txt", |stop: &GtfsStop| {
+            stop.stop_id == station || stop.parent_station.as_deref() == Some(station)
+        })?
+        .into_iter()
+        .map(|stop| stop.stop_id)
+        .collect();
+        if stop_ids.is_empty() {
+            return Err(format!("station {} is not in GTFS feed {}", station, path.display()).into());
+        }
+        let stop_times = read_table(&mut archive, "stop_times.txt", |stop_time: &GtfsStopTime| {
+            stop_ids.contains(&stop_time.stop_id)
+        })?;
+        let trip_ids: HashSet<&str> = stop_times
+            .iter()
+            .map(|stop_time| stop_time.trip_id.as_str())
+            .collect();
+        let trips = read_table(&mut archive, "trips.txt", |trip: &GtfsTrip| {
+            trip_ids.contains(trip.trip_id.as_str())
+        })?
+        .into_iter()
+        .map(|trip| (trip.trip_id.clone(), trip))
+        .collect();
+        // feeds may use only one of the two calendar files
+        let calendars = read_optional_table(&mut archive, "calendar.txt")?;
+        let calendar_dates = read_optional_table(&mut archive, "calendar_dates.txt")?;
+        Ok(GtfsFeed {
+            stop_ids,
+            trips,
+            stop_times,
+            calendars,
+            calendar_dates,
+        })
+    }
+
+    /// Whether the stop is the station or one of its platforms
+    pub fn serves(&self, stop_id: &str) -> bool {
+        self.stop_ids.contains(stop_id)
+    }
+
+    /// A trip that stops at the station
+    pub fn trip(&self, trip_id: &str) -> Option<&GtfsTrip> {
+        self.trips.get(trip_id)
+    }
+
+    /// Where a trip stops at the station
+    pub fn station_stop_time(&self, trip_id: &str) -> Option<&GtfsStopTime> {
+        self.stop_times
+            .iter()
+            .find(|stop_time| stop_time.trip_id == trip_id)
+    }
+
+    /// Ids of the routes with trips stopping at the station, sorted
+    pub fn routes(&self) -> Vec<String> {
+        let mut routes: Vec<String> = self
+            .trips
+            .values()
+            .map(|trip| trip.route_id.clone())
+            .collect();
+        routes.sort();
+        routes.dedup();
+        routes
+    }
+
+    /// Ids of the services that run on the service date
+    pub fn active_services(&self, service_date: NaiveDate) -> HashSet<String> {
+        let date = service_date.format("%Y%m%d").to_string();
+        let mut services: HashSet<String> = self
+            .calendars
+            .iter()
+            .filter(|calendar| {
+                calendar.start_date <= date
+                    && date <= calendar.end_date
+                    && runs_on(calendar, service_date.weekday())
+            })
+            .map(|calendar| calendar.service_id.clone())
+            .collect();
+        for exception in self
+            .calendar_dates
+            .iter()
+            .filter(|exception| exception.date == date)
+        {
+            match exception.exception_type {
+                1 => {
+                    services.insert(exception.service_id.clone());
+                }
+                2 => {
+                    services.remove(&exception.service_id);
+                }
+                _ => (),
+            }
+        }
+        services
+    }
+
+    /// Computes the scheduled departures from the station for the routes and direction on a
+    /// service day, at the time of the event in the agency's time zone, sorted by time
+    pub fn scheduled_departures(
+        &self,
+        routes: &[String],
+        direction_id: u8,
+        service_date: NaiveDate,
+        event: TimeEvent,
+        time_zone: Tz,
+    ) -> Vec<Departure> {
+        let services = self.active_services(service_date);
+        let mut departures: Vec<Departure> = self
+            .stop_times
+            .iter()
+            .filter_map(|stop_time| {
+                let trip = self.trips.get(&stop_time.trip_id)?;
+                if !services.contains(&trip.service_id)
+                    || !routes.contains(&trip.route_id)
+                    || trip.direction_id != Some(direction_id)
+                {
+                    return None;
+                }
+                let time = event.pick(
+                    stop_time.arrival_time.as_deref(),
+                    stop_time.departure_time.as_deref(),
+                )?;
+                Some(Departure {
+                    trip_id: trip.trip_id.clone(),
+                    route_id: trip.route_id.clone(),
+                    headsign: trip.trip_headsign.clone(),
+                    direction_id,
+                    time: service_time(service_date, time, time_zone)?,
+                    source: TimeSource::Scheduled,
+                    status: None,
+                    stop_sequence: Some(stop_time.stop_sequence),
+                    cancelled: false,
+                    next_service_day: false,
+                    destination_time: None,
+                    vehicle: None,
+                })
+            })
+            .collect();
+        departures.sort_by_key(|departure| departure.time);
+        departures
+    }
+
+    /// The scheduled departures after now, including trips of the previous service day that run
+    /// past midnight.  Once the current service day is over the departures of the next service day
+    /// are returned instead.  Returns None if there are none
+    pub fn upcoming_departures(
+        &self,
+        routes: &[String],
+        direction_id: u8,
+        event: TimeEvent,
+        now: DateTime<Tz>,
+    ) -> Option<Vec<Departure>> {
+        let current = service_date(now);
+        let upcoming = |service_dates: &[NaiveDate]| -> Vec<Departure> {
+            let mut departures: Vec<Departure> = service_dates
+                .iter()
+                .flat_map(|service_date| {
+                    self.scheduled_departures(routes, direction_id, *service_date, event, now.timezone())
+                })
+                .filter(|departure| departure.time > now)
+                .collect();
+            departures.sort_by_key(|departure| departure.time);
+            departures
+        };
+        let mut departures = upcoming(&[current.pred_opt()?, current]);
+        if departures.is_empty() {
+            departures = upcoming(&[current.succ_opt()?]);
+            for departure in departures.iter_mut() {
+                departure.next_service_day = true;
+            }
+        }
+        if departures.is_empty() {
+            return None;
+        }
+        Some(departures)
+    }
+}
+
+/// Whether a calendar runs on the weekday
+fn runs_on(calendar: &GtfsCalendar, weekday: Weekday) -> bool {
+    let runs = match weekday {
+        Weekday::Mon => calendar.monday,
+        Weekday::Tue => calendar.tuesday,
+        Weekday::Wed => calendar.wednesday,
+        Weekday::Thu => calendar.thursday,
+        Weekday::Fri => calendar.friday,
+        Weekday::Sat => calendar.saturday,
+        Weekday::Sun => calendar.sunday,
+    };
+    runs == 1
+}
+
+/// Converts a GTFS HH:MM:SS time on a service day to a date and time in the agency's time zone.
+/// GTFS measures times from noon minus 12 hours, which is midnight except on days the clocks
+/// change
+pub fn service_time(service_date: NaiveDate, time: &str, time_zone: Tz) -> Option<DateTime<Tz>> {
+    let mut parts = time.trim().split(':').map(|part| part.parse::<i64>());
+    let (hours, minutes, seconds) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(hours)), Some(Ok(minutes)), Some(Ok(seconds))) => (hours, minutes, seconds),
+        _ => return None,
+    };
+    let noon = time_zone
+        .from_local_datetime(&service_date.and_hms_opt(12, 0, 0)?)
+        .single()?;
+    Some(noon - Duration::hours(12) + Duration::seconds(hours * 3600 + minutes * 60 + seconds))
+}
+
+/// Reads the rows of a csv file in the feed that pass the filter, one row at a time so that
+/// large files like stop_times.txt are never fully in memory
+fn read_table<T: DeserializeOwned>(
+    archive: &mut zip::ZipArchive<File>,
+    name: &str,
+    mut keep: impl FnMut(&T) -> bool,
+) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    let file = archive
+        .by_name(name)
+        .map_err(|err| format!("GTFS feed has no {}: {}", name, err))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(file);
+    let mut rows = Vec::new();
+    for row in reader.deserialize() {
+        let row: T = row.map_err(|err| format!("malformed {} in GTFS feed: {}", name, err))?;
+        if keep(&row) {
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+/// Reads every row of a csv file in the feed, or nothing if the feed does not have the file
+fn read_optional_table<T: DeserializeOwned>(
+    archive: &mut zip::ZipArchive<File>,
+    name: &str,
+) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    if archive.file_names().any(|file_name| file_name == name) {
+        read_table(archive, name, |_| true)
+    } else {
+        Ok(Vec::new())
+    }
+}
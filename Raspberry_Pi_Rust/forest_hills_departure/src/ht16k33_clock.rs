@@ -3,7 +3,7 @@ extern crate ht16k33;
 extern crate rppal;
 extern crate std;
 
-use chrono::prelude::*;
+use crate::train_time::Departure;
 use rppal::i2c::I2c;
 use std::collections::HashMap;
 
@@ -39,6 +39,10 @@ pub struct ClockDisplay {
     minutes_single: Option<u8>,
     seconds_ten: Option<u8>,
     seconds_single: Option<u8>,
+    // whether the decimal point marking a timetabled, not predicted, time is on
+    scheduled: Option<bool>,
+    // whether the blinking decimal point marking out of date departures is on
+    stale: Option<bool>,
 }
 
 // Functions to initialize and change clock display
@@ -61,23 +65,37 @@ impl ClockDisplay {
             minutes_single: None,
             seconds_ten: None,
             seconds_single: None,
+            scheduled: None,
+            stale: None,
         })
     }
 
-    /// Dispalys the minutes:seconds until the next train on the clock display
+    /// Dispalys the minutes:seconds until the next train on the clock display.  A decimal point
+    /// after the minutes is lit when the time is from the timetable instead of a prediction.
+    /// Cancelled trains are skipped
     pub fn display_time_until(
         &mut self,
-        train_times: &Vec<chrono::DateTime<Local>>,
+        departures: &[Departure],
         minimum_display_min: &i64,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        // get now time in UTC
-        let now = chrono::Local::now();
+        let departures: Vec<&Departure> = departures
+            .iter()
+            .filter(|departure| !departure.cancelled)
+            .collect();
+        if departures.is_empty() {
+            self.clear_display()?;
+            return Ok(());
+        }
+        // get now time in UTC, the difference is the same in every time zone
+        let now = chrono::Utc::now();
         // get the difference between now and the train time
-        let mut diff = train_times[0].signed_duration_since(now);
+        let mut departure = departures[0];
+        let mut diff = departure.time.signed_duration_since(now);
         // if difference is less than minumum display, use next train
         if diff.num_minutes() < *minimum_display_min {
-            if train_times.len() > 1usize {
-                diff = train_times[1].signed_duration_since(now)
+            if departures.len() > 1usize {
+                departure = departures[1];
+                diff = departure.time.signed_duration_since(now)
             } else {
                 // if there is not a next train, clear display and end
                 self.clear_display()?;
@@ -100,7 +118,7 @@ impl ClockDisplay {
             // fourth digit, which is the seconds single
             let fourth = (seconds as u8) % 10u8;
             // if current display has no values, then display all of the new values
-            if vec![
+            if [
                 self.minutes_ten,
                 self.minutes_single,
                 self.seconds_ten,
@@ -133,11 +151,29 @@ impl ClockDisplay {
                     self.seconds_single = Some(fourth);
                 }
             }
+            // mark timetabled times, only switching the led when it changes
+            let scheduled = !departure.is_predicted();
+            if Some(scheduled) != self.scheduled {
+                self.display_decimal(2u8, scheduled)?;
+                self.scheduled = Some(scheduled);
+            }
         } else {
             // if minutes is greater than 100 clear dispaly and set all values to none
             self.clear_display()?;
         };
-        return Ok(())
+        Ok(())
+    }
+
+    /// Blinks the decimal point after the last digit while the departures are out of date.  Needs
+    /// to be called several times a second for the blinking
+    pub fn display_stale(&mut self, stale: bool) -> Result<(), Box<dyn std::error::Error>> {
+        // lit for the first half of every second
+        let lit = stale && chrono::Utc::now().timestamp_subsec_millis() < 500;
+        if Some(lit) != self.stale {
+            self.display_decimal(8u8, lit)?;
+            self.stale = Some(lit);
+        }
+        Ok(())
     }
 
     /// Clears clock display
@@ -147,6 +183,8 @@ impl ClockDisplay {
         self.minutes_single = None;
         self.seconds_ten = None;
         self.seconds_single = None;
+        self.scheduled = None;
+        self.stale = None;
         // clear the display buffer then push to clock to create a clear clock
         self.display.clear_display_buffer();
         self.display.write_display_buffer()?;
@@ -174,13 +212,13 @@ impl ClockDisplay {
             self.switch_leds(leds, 8, true)?;
         }
         self.display_colon(true)?;
-        return Ok(());
+        Ok(())
     }
 
     /// Turns on/off the necessary leds for a number at the indicated location
     fn switch_leds(
         &mut self,
-        leds: &Vec<u8>,
+        leds: &[u8],
         location: u8,
         on: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -204,6 +242,14 @@ impl ClockDisplay {
         Ok(())
     }
 
+    /// Turns on/off the decimal point after the digit at the indicated location
+    fn display_decimal(&mut self, location: u8, on: bool) -> Result<(), Box<dyn std::error::Error>> {
+        // the decimal point is led 7 of each digit
+        let led_location = ht16k33::LedLocation::new(location, 7u8)?;
+        self.display.set_led(led_location, on)?;
+        Ok(())
+    }
+
     fn change_number(
         &mut self,
         location: u8,
@@ -227,7 +273,7 @@ impl ClockDisplay {
                 .iter()
                 .filter_map(|led| {
                     if !new_leds.contains(led) {
-                        Some(led.clone())
+                        Some(*led)
                     } else {
                         None
                     }
@@ -240,7 +286,7 @@ impl ClockDisplay {
                 .iter()
                 .filter_map(|led| {
                     if !old_leds.contains(led) {
-                        Some(led.clone())
+                        Some(*led)
                     } else {
                         None
                     }
@@ -253,6 +299,6 @@ impl ClockDisplay {
         } else {
             self.switch_leds(new_leds, location, true)?
         };
-        return Ok(());
+        Ok(())
     }
 }
@@ -1,6 +1,17 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod agency_time;
+pub mod alerts;
+pub mod atomic_file;
+pub mod config;
+pub mod departure_cache;
+pub mod departure_source;
+pub mod gtfs_realtime;
+pub mod gtfs_static;
+pub mod mbta_api;
+pub mod prediction_stream;
 pub mod ssd1306_screen;
+pub mod station_catalog;
 pub mod train_time;
 pub mod ht16k33_clock;
@@ -1,91 +1,490 @@
 extern crate rppal;
 extern crate std;
-use clap::{Arg, App};
-use scraper::{Html, Selector};
-use std::collections::HashMap;
-use regex::Regex;
-use lazy_static::lazy_static;
-use reqwest;
-
-use forest_hills_departure;
+use clap::{App, AppSettings, Arg, SubCommand};
+
+use forest_hills_departure::agency_time;
+use forest_hills_departure::alerts::{banner_text, service_alerts, DEFAULT_MIN_SEVERITY};
+use forest_hills_departure::config::{Config, SourceKind};
+use forest_hills_departure::departure_cache::{CachedDepartures, DEFAULT_MAX_AGE_MIN};
+use forest_hills_departure::departure_source::{self, DepartureSource};
+use forest_hills_departure::mbta_api::RateLimited;
+use forest_hills_departure::station_catalog::{Station, StationCatalog, STATION_ROUTE_TYPES};
+use forest_hills_departure::train_time::{
+    Departure, DepartureQuery, Direction, EndpointHealth, MergedDepartures, StreamUpdate,
+    TripFilter,
+};
+use chrono::{DateTime, Duration};
+use chrono_tz::Tz;
+use log::{info, warn};
+
 // use rppal::gpio;
 use std::{
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread, time,
 };
 
-lazy_static! {
-    static ref TRAIN_SPLIT: Regex = Regex::new("place-|Boat-").unwrap();
+/// Number of stream connections in a row that may fail before falling back to polling
+const STREAM_ATTEMPTS: u32 = 5;
+
+/// Seconds between departure refreshes when polling
+const REFRESH_SECS: u64 = 60;
+
+/// Seconds before the first retry after failing to retrieve departures, doubled with every
+/// failure after that up to MAX_RETRY_SECS
+const RETRY_SECS: u64 = 5;
+
+/// Longest wait between retries
+const MAX_RETRY_SECS: u64 = 300;
+
+/// Minutes without a successful refresh, or while streaming without hearing from the stream, after
+/// which the departures are shown as out of date
+const STALE_AFTER_MIN: i64 = 3;
+
+/// The latest departures, shared between the fetching thread and the display loop
+struct TrainTimes {
+    departures: Option<Vec<Departure>>,
+    // when the departures were last retrieved, None if they never were
+    fetched_at: Option<DateTime<Tz>>,
+    // how the predictions and schedules the departures are merged from were last retrieved
+    predictions: EndpointHealth,
+    schedules: EndpointHealth,
+    // when the board started, for going stale if departures are never retrieved
+    started_at: DateTime<Tz>,
+    // when a prediction stream last sent anything, keep alives included, None if none ever did.
+    // Quiet spells without changes are not stale while the stream is heard from
+    heard_at: Option<DateTime<Tz>>,
+    // file the departures are kept in across restarts, None if they are not
+    cache_path: Option<PathBuf>,
+    // when the departures were last saved to the cache, which is at most every REFRESH_SECS so
+    // streamed changes do not wear out the SD card
+    saved_at: Option<DateTime<Tz>>,
+    // what the departures are retrieved for, including the agency's time zone
+    query: DepartureQuery,
+}
+
+impl TrainTimes {
+    /// Starts from the departures cached for the query if there are any younger than max_age, so
+    /// the board has something to show before the network is up
+    fn new(cache_path: Option<PathBuf>, max_age: Duration, query: DepartureQuery) -> TrainTimes {
+        let now = agency_time::now(query.time_zone);
+        let cached = cache_path.as_ref().and_then(|path| {
+            CachedDepartures::load(path, &query, max_age, now).unwrap_or_else(|err| {
+                warn!("could not load cached train times: {}", err);
+                None
+            })
+        });
+        if let Some(cached) = &cached {
+            info!("showing cached train times from {}", cached.fetched_at.format("%H:%M"));
+        }
+        let (departures, fetched_at) = match cached {
+            Some(cached) => (cached.departures, Some(cached.fetched_at)),
+            None => (None, None),
+        };
+        TrainTimes {
+            departures,
+            fetched_at,
+            predictions: EndpointHealth::Ok,
+            schedules: EndpointHealth::Ok,
+            started_at: now,
+            heard_at: None,
+            cache_path,
+            saved_at: None,
+            query,
+        }
+    }
+
+    /// Replaces the departures and their health with newly retrieved ones and saves the
+    /// departures to the cache, unless they were saved less than REFRESH_SECS ago
+    fn update(&mut self, merged: MergedDepartures) {
+        let fetched_at = agency_time::now(self.query.time_zone);
+        self.departures = merged.departures;
+        self.predictions = merged.predictions;
+        self.schedules = merged.schedules;
+        self.fetched_at = Some(fetched_at);
+        let save_due = self.saved_at.is_none_or(|saved_at| {
+            fetched_at.signed_duration_since(saved_at) >= Duration::seconds(REFRESH_SECS as i64)
+        });
+        if let (Some(cache_path), true) = (&self.cache_path, save_due) {
+            let cached = CachedDepartures {
+                query: self.query.clone(),
+                fetched_at,
+                departures: self.departures.clone(),
+            };
+            if let Err(err) = cached.save(cache_path) {
+                warn!("could not cache train times: {}", err);
+            }
+            self.saved_at = Some(fetched_at);
+        }
+    }
+
+    /// Whether the departures have not been refreshed, or the stream not heard from, for too long
+    fn is_stale(&self, now: DateTime<Tz>) -> bool {
+        let since = self.fetched_at.max(self.heard_at).unwrap_or(self.started_at);
+        now.signed_duration_since(since) > Duration::minutes(STALE_AFTER_MIN)
+    }
+
+    /// The departures that have not left yet, which drop out of the kept departures while they can
+    /// not be refreshed.  None if there are none
+    fn upcoming(&self, now: DateTime<Tz>) -> Option<Vec<Departure>> {
+        let upcoming: Vec<Departure> = self
+            .departures
+            .iter()
+            .flatten()
+            .filter(|departure| departure.time > now)
+            .cloned()
+            .collect();
+        if upcoming.is_empty() {
+            return None;
+        }
+        Some(upcoming)
+    }
+}
+
+/// Train times shared between the fetching thread and the display loop
+type SharedTrainTimes = Arc<Mutex<TrainTimes>>;
+
+/// Seconds between service alert refreshes
+const ALERT_REFRESH_SECS: u64 = 120;
+
+/// What the command line asks for
+pub enum Command {
+    // show the departures on the displays
+    Display(Arguments),
+    // update the station catalog from the MBTA API, with the config the client comes from
+    RefreshStations(Config),
+}
+
+/// Parsed command line arguments
+pub struct Arguments {
+    query: DepartureQuery,
+    source: Box<dyn DepartureSource>,
+    // the config file settings with the command line arguments applied
+    config: Config,
+    // where the trains in the chosen direction are headed, None if the source has no route names
+    destination: Option<String>,
+    clock_brightness: u8,
 }
 
 fn main() {
-    let (dir_code, station, clock_brightness) = arguments().unwrap_or_else(|err| panic!("ERROR - train_times - {}", err));
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let arguments = match arguments().unwrap_or_else(|err| panic!("ERROR - arguments - {}", err)) {
+        Command::Display(arguments) => arguments,
+        Command::RefreshStations(config) => {
+            refresh_stations(&config)
+                .unwrap_or_else(|err| panic!("ERROR - refresh_stations - {}", err));
+            return;
+        }
+    };
+    let Arguments {
+        query,
+        mut source,
+        config,
+        destination,
+        clock_brightness,
+    } = arguments;
     let minimum_display_min = 5i64;
-    // get the initial time trains and put them in a thread safe value to be passed back and forth
-    // between threads
-    let train_times_option = Arc::new(Mutex::new(
-        forest_hills_departure::train_time::train_times(&dir_code, &station)
-            .unwrap_or_else(|err| panic!("ERROR - train_times - {}", err)),
-    ));
+    let time_zone = query.time_zone;
+    // thread safe train times to be passed back and forth between threads, starting from the
+    // cached ones and kept current by the fetching thread
+    let cache_max_age = Duration::minutes(config.cache_max_age_min.unwrap_or(DEFAULT_MAX_AGE_MIN));
+    let train_times_option: SharedTrainTimes = Arc::new(Mutex::new(TrainTimes::new(
+        config.cache_path(),
+        cache_max_age,
+        query.clone(),
+    )));
     // create a new clock struct, this initializes the display
     let mut clock = forest_hills_departure::ht16k33_clock::ClockDisplay::new(0x70, clock_brightness)
         .unwrap_or_else(|err| panic!("ERROR - ClockDisplay - {}", err));
     // create a new screen struct, this initializes the display
     let mut screen = forest_hills_departure::ssd1306_screen::ScreenDisplay::new(0x3c)
         .unwrap_or_else(|err| panic!("ERROR - ScreenDisplay - {}", err));
+    screen
+        .display_destination(destination)
+        .unwrap_or_else(|err| panic!("ERROR - display_destination - {}", err));
+    // alert texts for the screen banner, kept current in their own thread
+    let alerts = Arc::new(Mutex::new(Vec::new()));
+    if config.source == SourceKind::Mbta && !config.hide_alerts {
+        let alerts_clone = Arc::clone(&alerts);
+        let client = config.mbta_client();
+        let min_severity = config.alert_severity.unwrap_or(DEFAULT_MIN_SEVERITY);
+        let query = query.clone();
+        thread::spawn(move || loop {
+            match service_alerts(&client, &query.station, &query.routes, min_severity) {
+                Ok(new_alerts) => {
+                    *alerts_clone.lock().unwrap() = new_alerts.iter().map(banner_text).collect();
+                }
+                // alerts are extra information, so keep the last ones and try again later
+                Err(err) => warn!("could not retrieve alerts: {}", err),
+            }
+            thread::sleep(time::Duration::from_secs(ALERT_REFRESH_SECS));
+        });
+    }
+    let stream = config.stream;
     // clone the train_times to pass into thread
     let train_times_clone = Arc::clone(&train_times_option);
-    // In a new thread find train times every minute and replace train_times with new value
-    thread::spawn(move || loop {
-        thread::sleep(time::Duration::from_secs(60));
-        let new_train_times = forest_hills_departure::train_time::train_times(&dir_code, &station)
-            .unwrap_or_else(|err| panic!("ERROR - train_times - {}", err));
-        let mut old_train = train_times_clone.lock().unwrap();
-        *old_train = new_train_times;
+    // In a new thread keep the train times current, streaming changes if asked to, otherwise or
+    // if streaming keeps failing find train times every minute and replace train_times
+    thread::spawn(move || {
+        if stream {
+            stream_train_times(&mut source, &query, &train_times_clone);
+        }
+        poll_train_times(&mut source, &query, &train_times_clone);
     });
+    // whether train times are on the displays, so they are only cleared once the last one goes
+    let mut showing_trains = true;
     // continually update screen and clock every 0.25 seconds
     loop {
         thread::sleep(time::Duration::from_millis(250));
-        // access and lock train times
-        let train_times_unlocked = train_times_option.lock().unwrap();
+        let now = agency_time::now(time_zone);
+        // access and lock train times only long enough to copy what is displayed
+        let (upcoming, stale, fetched_at, predictions, schedules) = {
+            let train_times_unlocked = train_times_option.lock().unwrap();
+            (
+                train_times_unlocked.upcoming(now),
+                train_times_unlocked.is_stale(now),
+                train_times_unlocked.fetched_at,
+                train_times_unlocked.predictions.clone(),
+                train_times_unlocked.schedules.clone(),
+            )
+        };
         // if there are some train times, display on clock and screen
-        if let Some(train_times) = &*train_times_unlocked {
+        if let Some(train_times) = &upcoming {
             screen
-                .display_trains(&train_times)
+                .display_trains(train_times)
                 .unwrap_or_else(|err| panic!("ERROR - display_trains - {}", err));
             clock
-                .display_time_until(&train_times, &minimum_display_min)
+                .display_time_until(train_times, &minimum_display_min)
                 .unwrap_or_else(|err| panic!("ERROR - display_time_until - {}", err));
-        } else {
-            // if there are no train times, clear both displays
+            showing_trains = true;
+        } else if showing_trains {
+            // if there are no train times any more, clear both displays
             screen
                 .clear_display(true)
                 .unwrap_or_else(|err| panic!("ERROR - clear_display - {}", err));
             clock
                 .clear_display()
                 .unwrap_or_else(|err| panic!("ERROR - clear_display - {}", err));
+            showing_trains = false;
+        }
+        screen
+            .display_stale(stale, fetched_at)
+            .unwrap_or_else(|err| panic!("ERROR - display_stale - {}", err));
+        screen
+            .display_health(&predictions, &schedules)
+            .unwrap_or_else(|err| panic!("ERROR - display_health - {}", err));
+        clock
+            .display_stale(stale)
+            .unwrap_or_else(|err| panic!("ERROR - display_stale - {}", err));
+        screen
+            .display_alerts(&alerts.lock().unwrap())
+            .unwrap_or_else(|err| panic!("ERROR - display_alerts - {}", err));
+    }
+}
+
+/// Retrieves the departures into train_times every REFRESH_SECS.  A failed refresh keeps the last
+/// departures and is retried with an exponential backoff, so the board survives network outages
+fn poll_train_times(
+    source: &mut Box<dyn DepartureSource>,
+    query: &DepartureQuery,
+    train_times: &SharedTrainTimes,
+) {
+    let mut failures = 0u32;
+    loop {
+        let wait = match source.departures(query) {
+            Ok(new_train_times) => {
+                if failures > 0 {
+                    info!("retrieved train times again after {} failed tries", failures);
+                }
+                failures = 0;
+                train_times.lock().unwrap().update(new_train_times);
+                REFRESH_SECS
+            }
+            // the rate limit resets within a minute, so backing off any longer does not help
+            Err(err) if err.is::<RateLimited>() => {
+                warn!("{}, keeping the current train times", err);
+                REFRESH_SECS
+            }
+            Err(err) => {
+                failures += 1;
+                warn!("could not retrieve train times, keeping the current ones: {}", err);
+                RETRY_SECS
+                    .saturating_mul(2u64.saturating_pow(failures - 1))
+                    .min(MAX_RETRY_SECS)
+            }
+        };
+        thread::sleep(time::Duration::from_secs(wait));
+    }
+}
+
+/// Streams departure changes into train_times, reconnecting with a backoff whenever the stream
+/// closes.  Returns once STREAM_ATTEMPTS connections in a row failed or closed without receiving
+/// anything
+fn stream_train_times(
+    source: &mut Box<dyn DepartureSource>,
+    query: &DepartureQuery,
+    train_times: &SharedTrainTimes,
+) {
+    let mut failures = 0u32;
+    while failures < STREAM_ATTEMPTS {
+        let mut received = false;
+        let result = source.stream(query, &mut |update| {
+            received = true;
+            let mut train_times = train_times.lock().unwrap();
+            // the stream only sends predictions once they and the schedules were retrieved
+            if let StreamUpdate::Departures(new_train_times) = update {
+                train_times.update(MergedDepartures::healthy(new_train_times));
+            }
+            train_times.heard_at = Some(agency_time::now(train_times.query.time_zone));
+        });
+        if received {
+            failures = 0;
+        }
+        match result {
+            Ok(()) if received => info!("prediction stream closed, reconnecting"),
+            // a stream that closes before sending anything is as good as a failed one
+            Ok(()) => {
+                failures += 1;
+                warn!("prediction stream closed without sending anything");
+            }
+            Err(err) => {
+                failures += 1;
+                warn!("prediction stream failed: {}", err);
+            }
+        }
+        // back off before reconnecting, so a stream that keeps closing does not use up the rate
+        // limit
+        thread::sleep(time::Duration::from_secs(2u64.pow(failures.max(1))));
+    }
+    warn!("prediction stream keeps failing, polling every minute instead");
+}
+
+/// Looks a station up in the refreshed or bundled station catalog, without the network.  Stop ids
+/// missing from the catalog, such as bus stops, are looked up in the MBTA API once and added to
+/// the refreshed catalog so later starts need no network.  Returns the station, whose id is the
+/// stop id to query
+fn find_station(
+    config: &Config,
+    station_input: &str,
+) -> Result<Station, Box<dyn std::error::Error>> {
+    let stations_path = config.stations_path();
+    let mut catalog = StationCatalog::local(&stations_path)?;
+    if let Some(station) = catalog.find(station_input) {
+        return Ok(station.clone());
+    }
+    let unknown = || {
+        format!(
+            "unknown station {}.  Stations: {}, or any MBTA stop id",
+            station_input,
+            catalog.argument_names().join(", ")
+        )
+    };
+    let station = match StationCatalog::fetch_stop(&config.mbta_client(), station_input) {
+        Ok(Some(station)) => station,
+        Ok(None) => return Err(unknown().into()),
+        Err(err) => return Err(format!("{} ({})", unknown(), err).into()),
+    };
+    info!("found {:?} stop {} ({})", station.kind, station.id, station.name);
+    catalog.insert(station.clone());
+    if let Err(err) = catalog.save(&stations_path) {
+        warn!("could not add stop {} to the stations: {}", station.id, err);
+    }
+    Ok(station)
+}
+
+/// Finds the direction the input names on each route, by name, destination or direction id.
+/// Returns the direction id to query and the destinations in that direction.  Without route
+/// metadata, e.g. offline, only direction ids and the MBTA's inbound and outbound are accepted
+fn find_direction(
+    source: &mut Box<dyn DepartureSource>,
+    routes: &[String],
+    direction_input: &str,
+) -> Result<(String, Option<String>), Box<dyn std::error::Error>> {
+    let mut found: Vec<Direction> = Vec::new();
+    for route in routes {
+        let directions = match source.route_directions(route) {
+            Ok(directions) => directions,
+            Err(err) => {
+                warn!("could not check the directions of route {}: {}", route, err);
+                continue;
+            }
+        };
+        match directions.iter().find(|direction| direction.matches(direction_input)) {
+            Some(direction) => found.push(direction.clone()),
+            None => {
+                let names: Vec<String> = directions
+                    .iter()
+                    .map(|direction| format!("{} to {}", direction.name, direction.destination))
+                    .collect();
+                return Err(format!(
+                    "route {} has no direction {}.  Directions: {}",
+                    route,
+                    direction_input,
+                    names.join(", ")
+                )
+                .into());
+            }
+        }
+    }
+    let mut dir_codes: Vec<u8> = found.iter().map(|direction| direction.id).collect();
+    dir_codes.sort();
+    dir_codes.dedup();
+    match dir_codes[..] {
+        [dir_code] => {
+            let mut destinations: Vec<String> =
+                found.into_iter().map(|direction| direction.destination).collect();
+            destinations.dedup();
+            Ok((dir_code.to_string(), Some(destinations.join(" / "))))
+        }
+        [] => {
+            let dir_code = match direction_input.to_lowercase().as_str() {
+                "0" | "outbound" => "0",
+                "1" | "inbound" => "1",
+                _ => {
+                    return Err(format!(
+                        "could not look up direction {}, give its direction id, 0 or 1, instead",
+                        direction_input
+                    )
+                    .into())
+                }
+            };
+            Ok((dir_code.to_string(), None))
         }
+        _ => Err(format!(
+            "direction {} has different direction ids on the routes, give its id, 0 or 1, instead",
+            direction_input
+        )
+        .into()),
     }
 }
 
+/// Retrieves the station catalog from the MBTA API and saves it for looking stations up offline
+fn refresh_stations(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let catalog = StationCatalog::fetch(&config.mbta_client(), &STATION_ROUTE_TYPES)?;
+    let stations_path = config.stations_path();
+    catalog.save(&stations_path)?;
+    info!("saved {} stations to {}", catalog.stations.len(), stations_path.display());
+    Ok(())
+}
+
 /// Gets the command line arguments
-pub fn arguments() -> Result<(String, String, u8), Box<dyn std::error::Error>> {
-    // let stations: HashMap<&str, &str> = [("South_Station", "sstat"), ("Forest_Hills", "forhl")].iter().cloned().collect();
-    let stations = station_hasmap()?;
-    let mut input_stations: Vec<&str> = stations.keys().map(|key| key.as_str()).collect();
-    input_stations.sort();
+pub fn arguments() -> Result<Command, Box<dyn std::error::Error>> {
     let args = App::new("MBTA train departure display")
         .version("0.2.0")
         .author("Rory Coffey <coffeyrt@gmail.com>")
-        .about("Displays the departure of the Needham MBTA commuter rail")
+        .about("Displays the departure of MBTA trains from a station")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("refresh-stations")
+                .about("Retrieves the stations from the MBTA API into the stations file, so stations can be looked up offline"),
+        )
         .arg(
             Arg::with_name("direction")
                 .short("d")
                 .long("direction")
                 .takes_value(true)
                 .required(true)
-                .possible_values(&["inbound", "outbound"])
-                .help("Train direction"),
+                .help("Direction of travel by name or destination, with underscores for spaces, e.g. Inbound, North or Oak_Grove, or by direction id, 0 or 1.  Checked against the route(s)"),
         )
         .arg(
             Arg::with_name("station")
@@ -93,8 +492,142 @@ pub fn arguments() -> Result<(String, String, u8), Box<dyn std::error::Error>> {
                 .long("station")
                 .takes_value(true)
                 .required(true)
-                .possible_values(&input_stations)
-                .help("Train station.  Only setup for commuter rail right now"),
+                .help("Station or stop, e.g. Forest_Hills, with underscores for spaces.  Stop ids work too: stations like place-forhl, platforms like 70001, bus stops like 10642 and ferry piers like Boat-Long"),
+        )
+        .arg(
+            Arg::with_name("route")
+                .short("r")
+                .long("route")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .default_value("CR-Needham")
+                .help("MBTA route id(s) to display, e.g. CR-Needham or Orange.  Separate multiple routes with commas"),
+        )
+        .arg(
+            Arg::with_name("destination")
+                .long("destination")
+                .takes_value(true)
+                .help("Only show trips that let passengers off at this station or stop after the station, e.g. Ruggles, leaving out express and short turn trips that do not.  Looked up like --station"),
+        )
+        .arg(
+            Arg::with_name("headsign")
+                .long("headsign")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Only show trips whose headsign contains one of these, e.g. Needham_Heights.  Separate multiple headsigns with commas"),
+        )
+        .arg(
+            Arg::with_name("exclude_headsign")
+                .long("exclude_headsign")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .help("Leave out trips whose headsign contains one of these.  Separate multiple headsigns with commas"),
+        )
+        .arg(
+            Arg::with_name("event")
+                .long("event")
+                .takes_value(true)
+                .possible_values(&["departure", "arrival"])
+                .help("Count down to the trains' departures from the station, or to their arrivals.  Defaults to departure"),
+        )
+        .arg(
+            Arg::with_name("time_zone")
+                .long("time_zone")
+                .takes_value(true)
+                .help("Time zone of the agency, e.g. America/Chicago, used for the train times whatever the system's time zone.  Defaults to America/New_York"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .help("TOML configuration file.  Command line arguments override its settings"),
+        )
+        .arg(
+            Arg::with_name("source")
+                .long("source")
+                .takes_value(true)
+                .possible_values(&["mbta", "fixture", "gtfs"])
+                .help("Where departures are retrieved from.  Defaults to the live MBTA API"),
+        )
+        .arg(
+            Arg::with_name("stream")
+                .long("stream")
+                .help("Stream prediction changes as they happen instead of polling every minute"),
+        )
+        .arg(
+            Arg::with_name("api_url")
+                .long("api_url")
+                .takes_value(true)
+                .env("MBTA_API_URL")
+                .help("Base url of the MBTA V3 API.  Defaults to https://api-v3.mbta.com"),
+        )
+        .arg(
+            Arg::with_name("api_key")
+                .long("api_key")
+                .takes_value(true)
+                .env("MBTA_API_KEY")
+                .hide_env_values(true)
+                .help("MBTA V3 API key.  Raises the rate limit above the 20 requests a minute allowed without a key"),
+        )
+        .arg(
+            Arg::with_name("gtfs")
+                .long("gtfs")
+                .takes_value(true)
+                .help("GTFS static zip, e.g. MBTA_GTFS.zip, with the timetable for the gtfs source and to show when the MBTA API can not be reached"),
+        )
+        .arg(
+            Arg::with_name("trip_updates")
+                .long("trip_updates")
+                .takes_value(true)
+                .help("Url or file of the GTFS-Realtime TripUpdates feed for the gtfs source"),
+        )
+        .arg(
+            Arg::with_name("fixture_dir")
+                .long("fixture_dir")
+                .takes_value(true)
+                .help("Directory with recorded predictions.json and schedules.json for the fixture source"),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .help("File the last train times are kept in, to show after a restart.  Defaults to /var/tmp/forest_hills_departure.json"),
+        )
+        .arg(
+            Arg::with_name("cache_max_age")
+                .long("cache_max_age")
+                .takes_value(true)
+                .help("Minutes after being retrieved that cached train times are still shown after a restart.  Defaults to 120"),
+        )
+        .arg(
+            Arg::with_name("no_cache")
+                .long("no_cache")
+                .help("Do not keep the train times on disk"),
+        )
+        .arg(
+            Arg::with_name("stations")
+                .long("stations")
+                .takes_value(true)
+                .help("File refresh-stations keeps the station list in.  Defaults to /var/tmp/forest_hills_stations.json, the bundled list is used until it exists"),
+        )
+        .arg(
+            Arg::with_name("alert_severity")
+                .long("alert_severity")
+                .takes_value(true)
+                .help("Lowest severity of service alerts shown on the screen, 0-10.  Defaults to 5"),
+        )
+        .arg(
+            Arg::with_name("hide_alerts")
+                .long("hide_alerts")
+                .help("Do not show service alerts on the screen"),
+        )
+        .arg(
+            Arg::with_name("show_vehicle")
+                .long("show_vehicle")
+                .help("Look up the next trains' vehicles to show where the next train is, e.g. 3 stops away, and how full the trains are on the screen.  Takes two more MBTA API requests every refresh"),
         )
         .arg(
             Arg::with_name("clock_brightness")
@@ -104,57 +637,126 @@ pub fn arguments() -> Result<(String, String, u8), Box<dyn std::error::Error>> {
                 .help("Scale to set clock brightness, 0-9"),
         )
         .get_matches();
-    let mut dir_code = String::new();
-    let mut station = String::new();
     let clock_brightness;
-    // reforms direction input to the direction code used in the API
-    if let Some(direction_input) = args.value_of("direction") {
-        match direction_input{
-            "inbound" => dir_code = "1".to_string(),
-            "outbound" => dir_code = "0".to_string(),
-            _ => panic!("Unknown direction input")
+    let routes: Vec<String> = args
+        .values_of("route")
+        .map(|route_inputs| route_inputs.map(|route| route.to_string()).collect())
+        .unwrap_or_default();
+    // read the config file, then let the command line override it
+    let mut config = match args.value_of("config") {
+        Some(config_path) => Config::load(Path::new(config_path))?,
+        None => Config::default(),
+    };
+    if let Some(event_input) = args.value_of("event") {
+        config.event = event_input.parse()?;
+    }
+    if let Some(time_zone_input) = args.value_of("time_zone") {
+        config.time_zone = Some(time_zone_input.parse::<Tz>()?);
+    }
+    if let Some(source_input) = args.value_of("source") {
+        config.source = source_input.parse()?;
+    }
+    if args.is_present("stream") {
+        config.stream = true;
+    }
+    if let Some(api_url_input) = args.value_of("api_url") {
+        config.api_url = Some(api_url_input.to_string());
+    }
+    if let Some(api_key_input) = args.value_of("api_key") {
+        config.api_key = Some(api_key_input.to_string());
+    }
+    if let Some(cache_input) = args.value_of("cache") {
+        config.cache_path = Some(PathBuf::from(cache_input));
+    }
+    if let Some(max_age_input) = args.value_of("cache_max_age") {
+        config.cache_max_age_min = Some(max_age_input.parse::<i64>()?);
+    }
+    if args.is_present("no_cache") {
+        config.no_cache = true;
+    }
+    if let Some(severity_input) = args.value_of("alert_severity") {
+        config.alert_severity = Some(severity_input.parse::<u8>()?);
+    }
+    if args.is_present("hide_alerts") {
+        config.hide_alerts = true;
+    }
+    if args.is_present("show_vehicle") {
+        config.show_vehicle = true;
+    }
+    if let Some(gtfs_input) = args.value_of("gtfs") {
+        config.gtfs_path = Some(PathBuf::from(gtfs_input));
+    }
+    if let Some(trip_updates_input) = args.value_of("trip_updates") {
+        config.trip_updates = Some(trip_updates_input.to_string());
+    }
+    if let Some(fixture_input) = args.value_of("fixture_dir") {
+        config.fixture_dir = Some(PathBuf::from(fixture_input));
+    }
+    if let Some(stations_input) = args.value_of("stations") {
+        config.stations_path = Some(PathBuf::from(stations_input));
+    }
+    if args.subcommand_matches("refresh-stations").is_some() {
+        return Ok(Command::RefreshStations(config));
+    }
+    let station = find_station(&config, args.value_of("station").unwrap_or_default())?;
+    let mut source = departure_source::from_config(&config, &station.id)?;
+    // check that each route actually serves the station, otherwise nothing would ever display.
+    // The catalog knows the routes of its stations, so only stations without any are checked
+    // online, and without a network the check is skipped so the board can start with cached
+    // train times
+    let station_routes = if station.routes.is_empty() {
+        source.station_routes(&station.id).unwrap_or_else(|err| {
+            warn!("could not check the routes serving the station: {}", err);
+            routes.clone()
+        })
+    } else {
+        station.routes.clone()
+    };
+    for route in &routes {
+        if !station_routes.contains(route) {
+            return Err(format!(
+                "route {} does not serve the station.  Routes serving it: {}",
+                route,
+                station_routes.join(", ")
+            )
+            .into());
         }
+    }
+    let (dir_code, destination) =
+        find_direction(&mut source, &routes, args.value_of("direction").unwrap_or_default())?;
+    let headsigns = |name: &str| -> Vec<String> {
+        args.values_of(name)
+            .map(|headsign_inputs| headsign_inputs.map(|headsign| headsign.to_string()).collect())
+            .unwrap_or_default()
     };
-    if let Some(station_input) = args.value_of("station") {
-        station = stations.get(station_input).unwrap().to_string();
+    let mut filter = TripFilter {
+        destination: None,
+        include_headsigns: headsigns("headsign"),
+        exclude_headsigns: headsigns("exclude_headsign"),
     };
+    if let Some(destination_input) = args.value_of("destination") {
+        if config.source != SourceKind::Mbta {
+            warn!("only the mbta source knows which trips call at the destination, showing all");
+        }
+        filter.destination = Some(find_station(&config, destination_input)?.id);
+    }
     if let Some(clock_bright_input) = args.value_of("clock_brightness") {
         clock_brightness = clock_bright_input.parse::<u8>()?;
     }else{
         clock_brightness = 7u8;
     };
-    return Ok((dir_code, station, clock_brightness));
-}
-
-fn station_hasmap() -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    let subway_url = "https://www.mbta.com/stops/subway#subway-tab";
-    let communter_url = "https://www.mbta.com/stops/commuter-rail#commuter-rail-tab";
-    let ferry_url = "https://www.mbta.com/stops/ferry#ferry-tab";
-    let stations_info = get_stations(subway_url)?;
-    let mut station_conversion: HashMap<String, String> = stations_info.iter().cloned().collect();
-    station_conversion.extend(get_stations(communter_url)?);
-    station_conversion.extend(get_stations(ferry_url)?);
-    return Ok(station_conversion)
-}
-
-fn get_stations(url: &str) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
-    let website_text = reqwest::blocking::get(url)?.text()?;
-    let document = Html::parse_document(&website_text);
-    let selector = Selector::parse(r#"a[class="btn button stop-btn m-detailed-stop"]"#).unwrap();
-    let station_select = document.select(&selector);
-    let station_conversion: Vec<(String, String)> = station_select
-        .map(|button| (button
-                .value()
-                .attr("data-name")
-                .unwrap().replace(" ", "_")
-                .replace("'", ""), 
-                TRAIN_SPLIT.split(button
-                    .value()
-                    .attr("href")
-                    .unwrap())
-                .collect::<Vec<&str>>()
-                .last()
-                .unwrap()
-                .to_string())).collect();
-    return Ok(station_conversion)
+    Ok(Command::Display(Arguments {
+        query: DepartureQuery {
+            station: station.id,
+            dir_code,
+            routes,
+            filter,
+            event: config.event,
+            time_zone: config.time_zone(),
+        },
+        source,
+        config,
+        destination,
+        clock_brightness,
+    }))
 }
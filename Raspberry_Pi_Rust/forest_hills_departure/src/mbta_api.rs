@@ -0,0 +1,657 @@
+extern crate chrono;
+extern crate chrono_tz;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate std;
+
+use crate::agency_time::DEFAULT_TIME_ZONE;
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::{debug, warn};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::{fmt, sync::Mutex, thread, time};
+
+/// Error for MBTA API responses that do not match the expected JSON:API document
+#[derive(Debug)]
+pub enum ApiError {
+    /// The response could not be deserialized into the resource type
+    Malformed {
+        address: String,
+        source: serde_json::Error,
+    },
+    /// A resource in the response was not of the requested type
+    WrongType {
+        expected: &'static str,
+        found: String,
+        id: String,
+    },
+    /// A relationship pointed to a resource that is not in the `included` list
+    MissingIncluded { kind: String, id: String },
+    /// An included resource could not be deserialized into the resource type
+    MalformedIncluded {
+        kind: String,
+        id: String,
+        source: serde_json::Error,
+    },
+    /// A server-sent event from a streaming response could not be deserialized
+    MalformedEvent {
+        event: String,
+        source: serde_json::Error,
+    },
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Malformed { address, source } => {
+                write!(f, "malformed response from {}: {}", address, source)
+            }
+            ApiError::WrongType { expected, found, id } => write!(
+                f,
+                "expected resource of type {} but {} is of type {}",
+                expected, id, found
+            ),
+            ApiError::MissingIncluded { kind, id } => write!(
+                f,
+                "related {} {} is missing from the included resources",
+                kind, id
+            ),
+            ApiError::MalformedIncluded { kind, id, source } => {
+                write!(f, "malformed included {} {}: {}", kind, id, source)
+            }
+            ApiError::MalformedEvent { event, source } => {
+                write!(f, "malformed {} stream event: {}", event, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Malformed { source, .. } => Some(source),
+            ApiError::MalformedIncluded { source, .. } => Some(source),
+            ApiError::MalformedEvent { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Attributes of a JSON:API resource type, with the name used in the "type" field
+pub trait ResourceType: DeserializeOwned {
+    const TYPE: &'static str;
+}
+
+/// Type and id of a resource, used within relationships
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+}
+
+/// The data of a relationship, which is either one resource or a list of resources
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Linkage {
+    One(Identifier),
+    Many(Vec<Identifier>),
+}
+
+/// A relationship from one resource to others.  Data is None when the relationship is empty
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Relationship {
+    #[serde(default)]
+    pub data: Option<Linkage>,
+}
+
+/// A single JSON:API resource with its attributes deserialized into `A`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Resource<A> {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub attributes: A,
+    #[serde(default)]
+    pub relationships: HashMap<String, Relationship>,
+}
+
+impl<A> Resource<A> {
+    /// Returns the identifier of a to-one relationship, if present
+    pub fn related_identifier(&self, relationship: &str) -> Option<&Identifier> {
+        match self.relationships.get(relationship)?.data.as_ref()? {
+            Linkage::One(identifier) => Some(identifier),
+            Linkage::Many(_) => None,
+        }
+    }
+
+    /// Returns the id of a to-one relationship, if present
+    pub fn related_id(&self, relationship: &str) -> Option<&str> {
+        self.related_identifier(relationship)
+            .map(|identifier| identifier.id.as_str())
+    }
+}
+
+/// A JSON:API document containing a list of resources and the related resources that were
+/// requested with `include=`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Document<A> {
+    pub data: Vec<Resource<A>>,
+    #[serde(default)]
+    pub included: Vec<Resource<Value>>,
+}
+
+impl<A: ResourceType> Document<A> {
+    /// Parses the body of a response from `address` and checks each resource type
+    pub fn parse(address: &str, body: &str) -> Result<Document<A>, ApiError> {
+        let document: Document<A> =
+            serde_json::from_str(body).map_err(|source| ApiError::Malformed {
+                address: address.to_string(),
+                source,
+            })?;
+        if let Some(resource) = document.data.iter().find(|resource| resource.kind != A::TYPE) {
+            return Err(ApiError::WrongType {
+                expected: A::TYPE,
+                found: resource.kind.clone(),
+                id: resource.id.clone(),
+            });
+        }
+        Ok(document)
+    }
+
+    /// Resolves the to-one relationship of a resource within this document's included
+    /// resources.  Returns None when the relationship is empty or absent
+    pub fn related<B: ResourceType>(
+        &self,
+        resource: &Resource<A>,
+        relationship: &str,
+    ) -> Result<Option<Resource<B>>, ApiError> {
+        let identifier = match resource.related_identifier(relationship) {
+            Some(identifier) => identifier,
+            None => return Ok(None),
+        };
+        if identifier.kind != B::TYPE {
+            return Err(ApiError::WrongType {
+                expected: B::TYPE,
+                found: identifier.kind.clone(),
+                id: identifier.id.clone(),
+            });
+        }
+        let included = self
+            .included
+            .iter()
+            .find(|included| included.kind == identifier.kind && included.id == identifier.id)
+            .ok_or_else(|| ApiError::MissingIncluded {
+                kind: identifier.kind.clone(),
+                id: identifier.id.clone(),
+            })?;
+        let attributes = B::deserialize(&included.attributes).map_err(|source| {
+            ApiError::MalformedIncluded {
+                kind: included.kind.clone(),
+                id: included.id.clone(),
+                source,
+            }
+        })?;
+        Ok(Some(Resource {
+            id: included.id.clone(),
+            kind: included.kind.clone(),
+            attributes,
+            relationships: included.relationships.clone(),
+        }))
+    }
+}
+
+/// Host of the MBTA V3 API that is used unless another one is configured
+pub const DEFAULT_API_URL: &str = "https://api-v3.mbta.com";
+
+/// Number of times a rate limited request is retried before giving up
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Longest time to wait for the rate limit to reset before retrying
+const MAX_RATE_LIMIT_WAIT_SECS: i64 = 60;
+
+/// Percentage of the quota below which the requests left are logged as a warning
+const LOW_QUOTA_PERCENT: u32 = 10;
+
+/// Seconds a stream may go without sending anything, keep alives included, before its connection
+/// is taken to be dead.  The MBTA sends keep alives well within this
+const STREAM_SILENCE_SECS: u64 = 120;
+
+/// Request quota reported by the API in the x-ratelimit-* response headers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub remaining: u32,
+    // when the quota is refilled, in the agency's time zone
+    pub reset: DateTime<Tz>,
+}
+
+impl RateLimit {
+    /// Reads the rate limit headers of a response, None if any are missing
+    fn from_headers(headers: &HeaderMap, time_zone: Tz) -> Option<RateLimit> {
+        let header = |name: &str| headers.get(name)?.to_str().ok()?.parse::<i64>().ok();
+        Some(RateLimit {
+            limit: header("x-ratelimit-limit")? as u32,
+            remaining: header("x-ratelimit-remaining")? as u32,
+            reset: Utc
+                .timestamp_opt(header("x-ratelimit-reset")?, 0)
+                .single()?
+                .with_timezone(&time_zone),
+        })
+    }
+
+    /// Whether less than LOW_QUOTA_PERCENT of the quota is left
+    pub fn is_low(&self) -> bool {
+        self.remaining * 100 < self.limit * LOW_QUOTA_PERCENT
+    }
+}
+
+/// Error when the API kept refusing requests because the rate limit was used up
+#[derive(Debug)]
+pub struct RateLimited {
+    pub address: String,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rate limited by {}", self.address)?;
+        if let Some(rate_limit) = self.rate_limit {
+            write!(f, " until {}", rate_limit.reset.format("%H:%M:%S %Z"))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Client for an MBTA V3 API host
+#[derive(Debug)]
+pub struct MbtaClient {
+    base_url: String,
+    api_key: Option<String>,
+    // time zone the rate limit reset is given in
+    time_zone: Tz,
+    http: reqwest::blocking::Client,
+    // quota reported with the latest response
+    rate_limit: Mutex<Option<RateLimit>>,
+}
+
+impl Default for MbtaClient {
+    fn default() -> Self {
+        MbtaClient::new(DEFAULT_API_URL)
+    }
+}
+
+impl MbtaClient {
+    /// Creates a client for the API at base_url, e.g. https://api-v3.mbta.com
+    pub fn new(base_url: &str) -> MbtaClient {
+        MbtaClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: None,
+            time_zone: DEFAULT_TIME_ZONE,
+            http: reqwest::blocking::Client::new(),
+            rate_limit: Mutex::new(None),
+        }
+    }
+
+    /// Sends the key in the x-api-key header of every request, which raises the rate limit
+    /// above the 20 requests a minute allowed without a key
+    pub fn with_api_key(mut self, api_key: &str) -> MbtaClient {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Gives the rate limit reset in the agency's time zone, agency_time::DEFAULT_TIME_ZONE if not
+    /// set, rather than the time zone the Pi is left on
+    pub fn with_time_zone(mut self, time_zone: Tz) -> MbtaClient {
+        self.time_zone = time_zone;
+        self
+    }
+
+    /// The base url requests are sent to
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The quota reported with the latest response, None before the first response
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        *self.rate_limit.lock().unwrap()
+    }
+
+    /// Retrieves a JSON:API document of resources, with path being everything after the base
+    /// url, e.g. /routes?filter[stop]=place-forhl.  Rate limited requests are retried once the
+    /// quota resets
+    pub fn get<A: ResourceType>(&self, path: &str) -> Result<Document<A>, Box<dyn std::error::Error>> {
+        let address = format!("{}{}", self.base_url, path);
+        let mut attempt = 0u32;
+        loop {
+            let mut request = self.http.get(&address);
+            if let Some(api_key) = &self.api_key {
+                request = request.header("x-api-key", api_key);
+            }
+            let response = request.send()?;
+            let rate_limit = RateLimit::from_headers(response.headers(), self.time_zone);
+            if let Some(rate_limit) = rate_limit {
+                let quota = format!(
+                    "MBTA API quota: {} of {} requests left until {}",
+                    rate_limit.remaining,
+                    rate_limit.limit,
+                    rate_limit.reset.format("%H:%M:%S %Z")
+                );
+                if rate_limit.is_low() {
+                    warn!("{}", quota);
+                } else {
+                    debug!("{}", quota);
+                }
+                *self.rate_limit.lock().unwrap() = Some(rate_limit);
+            }
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                let body = response.error_for_status()?.text()?;
+                return Ok(Document::parse(&address, &body)?);
+            }
+            if attempt == RATE_LIMIT_RETRIES {
+                return Err(Box::new(RateLimited {
+                    address,
+                    rate_limit,
+                }));
+            }
+            attempt += 1;
+            // wait for the quota to reset, or back off exponentially when the reset is unknown
+            let wait_secs = match rate_limit {
+                Some(rate_limit) => rate_limit
+                    .reset
+                    .signed_duration_since(Utc::now())
+                    .num_seconds()
+                    .clamp(1, MAX_RATE_LIMIT_WAIT_SECS),
+                None => 2i64.pow(attempt),
+            };
+            warn!(
+                "MBTA API rate limit reached, retrying in {} seconds.  Setting an API key raises the limit",
+                wait_secs
+            );
+            thread::sleep(time::Duration::from_secs(wait_secs as u64));
+        }
+    }
+
+    /// Opens a server-sent event stream of resources, with path being everything after the base
+    /// url.  The returned reader blocks until the MBTA sends the next event or keep alive, and
+    /// fails once it has waited STREAM_SILENCE_SECS
+    pub fn stream(&self, path: &str) -> Result<impl BufRead, Box<dyn std::error::Error>> {
+        let address = format!("{}{}", self.base_url, path);
+        // the stream stays open indefinitely, so it can not share the timeout of normal requests.
+        // Instead every read times out, so a connection that died silently is given up on
+        let http = reqwest::blocking::Client::builder()
+            .timeout(time::Duration::from_secs(STREAM_SILENCE_SECS))
+            .build()?;
+        let mut request = http.get(&address).header("accept", "text/event-stream");
+        if let Some(api_key) = &self.api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        let response = request.send()?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Box::new(RateLimited {
+                address,
+                rate_limit: RateLimit::from_headers(response.headers(), self.time_zone),
+            }));
+        }
+        Ok(BufReader::new(response.error_for_status()?))
+    }
+}
+
+/// Realtime prediction of a trip arriving at or departing from a stop
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Prediction {
+    pub arrival_time: Option<DateTime<FixedOffset>>,
+    pub departure_time: Option<DateTime<FixedOffset>>,
+    pub direction_id: Option<u8>,
+    pub schedule_relationship: Option<String>,
+    pub status: Option<String>,
+    pub stop_sequence: Option<u32>,
+}
+
+impl ResourceType for Prediction {
+    const TYPE: &'static str = "prediction";
+}
+
+/// Timetabled arrival and departure of a trip at a stop
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub arrival_time: Option<DateTime<FixedOffset>>,
+    pub departure_time: Option<DateTime<FixedOffset>>,
+    pub direction_id: Option<u8>,
+    pub stop_sequence: Option<u32>,
+    pub pickup_type: Option<u8>,
+    pub drop_off_type: Option<u8>,
+    pub timepoint: Option<bool>,
+}
+
+impl ResourceType for Schedule {
+    const TYPE: &'static str = "schedule";
+}
+
+/// A single journey of a vehicle along a route
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Trip {
+    pub headsign: Option<String>,
+    pub name: Option<String>,
+    pub direction_id: Option<u8>,
+    pub block_id: Option<String>,
+}
+
+impl ResourceType for Trip {
+    const TYPE: &'static str = "trip";
+}
+
+/// How a vehicle stands in relation to its current stop
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum VehicleStopStatus {
+    /// About to arrive at the stop
+    IncomingAt,
+    /// Standing at the stop
+    StoppedAt,
+    /// Has left the previous stop and is on the way to the stop
+    InTransitTo,
+}
+
+/// How crowded a vehicle or carriage is, from GTFS-Realtime's occupancy status
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OccupancyStatus {
+    Empty,
+    ManySeatsAvailable,
+    FewSeatsAvailable,
+    StandingRoomOnly,
+    CrushedStandingRoomOnly,
+    Full,
+    NotAcceptingPassengers,
+    NoDataAvailable,
+    NotBoardable,
+}
+
+/// One carriage of a vehicle, on lines that report occupancy per carriage
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Carriage {
+    pub label: Option<String>,
+    pub occupancy_status: Option<OccupancyStatus>,
+    // how full the carriage is, 0 to 100
+    pub occupancy_percentage: Option<u8>,
+}
+
+/// A vehicle running a trip, where it is along it and how full it is
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Vehicle {
+    pub current_status: Option<VehicleStopStatus>,
+    pub current_stop_sequence: Option<u32>,
+    pub label: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub occupancy_status: Option<OccupancyStatus>,
+    // from the front of the train, empty on lines that do not report them
+    #[serde(default)]
+    pub carriages: Vec<Carriage>,
+    pub updated_at: Option<DateTime<FixedOffset>>,
+}
+
+impl ResourceType for Vehicle {
+    const TYPE: &'static str = "vehicle";
+}
+
+/// A station, platform or stop
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Stop {
+    pub name: String,
+    pub description: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    #[serde(default)]
+    pub location_type: u8,
+    pub platform_code: Option<String>,
+    pub platform_name: Option<String>,
+    pub vehicle_type: Option<u8>,
+}
+
+impl ResourceType for Stop {
+    const TYPE: &'static str = "stop";
+}
+
+/// A route, e.g. a subway line, commuter rail line or bus route
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Route {
+    pub long_name: String,
+    pub short_name: String,
+    #[serde(rename = "type")]
+    pub route_type: u8,
+    #[serde(default)]
+    pub direction_names: Vec<Option<String>>,
+    #[serde(default)]
+    pub direction_destinations: Vec<Option<String>>,
+    pub color: Option<String>,
+    pub description: Option<String>,
+}
+
+impl ResourceType for Route {
+    const TYPE: &'static str = "route";
+}
+
+/// Period during which an alert is in effect.  End is None while it is open ended
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ActivePeriod {
+    pub start: DateTime<FixedOffset>,
+    pub end: Option<DateTime<FixedOffset>>,
+}
+
+/// A service alert, e.g. a delay, shuttle diversion or station closure
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub header: String,
+    pub short_header: Option<String>,
+    pub effect: String,
+    // 0 (least severe) to 10 (most severe)
+    pub severity: u8,
+    pub lifecycle: Option<String>,
+    #[serde(default)]
+    pub active_period: Vec<ActivePeriod>,
+}
+
+impl Alert {
+    /// Whether any active period of the alert contains the time
+    pub fn is_active(&self, time: DateTime<Utc>) -> bool {
+        self.active_period.iter().any(|period| {
+            period.start <= time && period.end.is_none_or(|end| time < end)
+        })
+    }
+}
+
+impl ResourceType for Alert {
+    const TYPE: &'static str = "alert";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "https://api-v3.mbta.com/predictions";
+
+    /// A prediction document whose prediction is related to the trip, with the included resources
+    fn predictions(trip_id: &str, included: &str) -> String {
+        format!(
+            r#"{{
+                "data": [{{
+                    "id": "prediction-CR-608-place-forhl",
+                    "type": "prediction",
+                    "attributes": {{"departure_time": "2021-06-14T08:04:00-04:00"}},
+                    "relationships": {{"trip": {{"data": {{"type": "trip", "id": "{}"}}}}}}
+                }}],
+                "included": [{}]
+            }}"#,
+            trip_id, included
+        )
+    }
+
+    const TRIP_608: &str = r#"{
+        "id": "CR-608",
+        "type": "trip",
+        "attributes": {"headsign": "South Station", "name": "608"}
+    }"#;
+
+    #[test]
+    fn documents_without_data_are_malformed() {
+        let err = Document::<Prediction>::parse(ADDRESS, r#"{"included": []}"#).unwrap_err();
+        match err {
+            ApiError::Malformed { address, .. } => assert_eq!(address, ADDRESS),
+            err => panic!("expected a malformed document, got {}", err),
+        }
+    }
+
+    #[test]
+    fn resources_of_another_type_are_rejected() {
+        let body =
+            predictions("CR-608", "").replace(r#""type": "prediction""#, r#""type": "schedule""#);
+        let err = Document::<Prediction>::parse(ADDRESS, &body).unwrap_err();
+        match err {
+            ApiError::WrongType { expected, found, id } => {
+                assert_eq!(expected, "prediction");
+                assert_eq!(found, "schedule");
+                assert_eq!(id, "prediction-CR-608-place-forhl");
+            }
+            err => panic!("expected a resource of the wrong type, got {}", err),
+        }
+    }
+
+    #[test]
+    fn relationships_to_resources_not_included_are_missing() {
+        let document =
+            Document::<Prediction>::parse(ADDRESS, &predictions("CR-610", TRIP_608)).unwrap();
+        let err = document.related::<Trip>(&document.data[0], "trip").unwrap_err();
+        match err {
+            ApiError::MissingIncluded { kind, id } => {
+                assert_eq!(kind, "trip");
+                assert_eq!(id, "CR-610");
+            }
+            err => panic!("expected a missing included resource, got {}", err),
+        }
+    }
+
+    #[test]
+    fn relationships_resolve_to_included_resources() {
+        let document =
+            Document::<Prediction>::parse(ADDRESS, &predictions("CR-608", TRIP_608)).unwrap();
+        let prediction = &document.data[0];
+        assert_eq!(prediction.related_id("trip"), Some("CR-608"));
+        let trip = document.related::<Trip>(prediction, "trip").unwrap().unwrap();
+        assert_eq!(trip.id, "CR-608");
+        assert_eq!(trip.attributes.headsign.as_deref(), Some("South Station"));
+        assert_eq!(trip.attributes.name.as_deref(), Some("608"));
+        // relationships the resource does not have are empty rather than errors
+        assert!(document.related::<Stop>(prediction, "stop").unwrap().is_none());
+    }
+}
@@ -0,0 +1,149 @@
+extern crate serde_json;
+extern crate std;
+
+use crate::mbta_api::{ApiError, Document, Identifier, Prediction, ResourceType, Resource};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// A single server-sent event
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ServerEvent {
+    pub event: String,
+    pub data: String,
+}
+
+/// Name given to the comments read between events, which the MBTA sends as keep alives
+pub const KEEP_ALIVE: &str = "keep-alive";
+
+/// Reads server-sent events from a text/event-stream response
+pub struct EventReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> EventReader<R> {
+    pub fn new(reader: R) -> EventReader<R> {
+        EventReader { reader }
+    }
+}
+
+impl<R: BufRead> Iterator for EventReader<R> {
+    type Item = std::io::Result<ServerEvent>;
+
+    /// Reads lines until the blank line that ends an event, or a comment between events, which is
+    /// returned as a KEEP_ALIVE event.  Returns None when the stream ends
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut event = ServerEvent::default();
+        let mut data_lines: Vec<String> = Vec::new();
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(err) => return Some(Err(err)),
+            }
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if line.is_empty() {
+                // a blank line dispatches the event, unless nothing was read, e.g. after a comment
+                if event.event.is_empty() && data_lines.is_empty() {
+                    continue;
+                }
+                event.data = data_lines.join("\n");
+                return Some(Ok(event));
+            }
+            // lines starting with a colon are comments, which the MBTA sends as keep alives.  They
+            // are passed on between events, so the reader can tell the stream is still open
+            if let Some(comment) = line.strip_prefix(':') {
+                if event.event.is_empty() && data_lines.is_empty() {
+                    return Some(Ok(ServerEvent {
+                        event: KEEP_ALIVE.to_string(),
+                        data: comment.trim_start_matches(' ').to_string(),
+                    }));
+                }
+                continue;
+            }
+            let (field, value) = match line.find(':') {
+                Some(colon) => (&line[..colon], line[colon + 1..].trim_start_matches(' ')),
+                None => (line, ""),
+            };
+            match field {
+                "event" => event.event = value.to_string(),
+                "data" => data_lines.push(value.to_string()),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// The current predictions, and the trips and stops included with them, kept up to date from the
+/// reset/add/update/remove events of the MBTA V3 predictions stream
+#[derive(Debug, Default)]
+pub struct PredictionSet {
+    // every resource received, by type and id
+    resources: HashMap<(String, String), Resource<Value>>,
+}
+
+impl PredictionSet {
+    /// Applies a stream event to the set.  Returns whether anything changed
+    pub fn apply(&mut self, event: &ServerEvent) -> Result<bool, ApiError> {
+        match event.event.as_str() {
+            "reset" => {
+                let resources: Vec<Resource<Value>> = parse_event(event)?;
+                self.resources = resources
+                    .into_iter()
+                    .map(|resource| ((resource.kind.clone(), resource.id.clone()), resource))
+                    .collect();
+                Ok(true)
+            }
+            "add" | "update" => {
+                let resource: Resource<Value> = parse_event(event)?;
+                self.resources
+                    .insert((resource.kind.clone(), resource.id.clone()), resource);
+                Ok(true)
+            }
+            "remove" => {
+                let identifier: Identifier = parse_event(event)?;
+                Ok(self
+                    .resources
+                    .remove(&(identifier.kind, identifier.id))
+                    .is_some())
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns the predictions as a document, with the other resources as its included resources
+    pub fn document(&self) -> Result<Document<Prediction>, ApiError> {
+        let mut data = Vec::new();
+        let mut included = Vec::new();
+        for resource in self.resources.values() {
+            if resource.kind == Prediction::TYPE {
+                let attributes =
+                    Prediction::deserialize(&resource.attributes).map_err(|source| {
+                        ApiError::MalformedEvent {
+                            event: format!("{} {}", resource.kind, resource.id),
+                            source,
+                        }
+                    })?;
+                data.push(Resource {
+                    id: resource.id.clone(),
+                    kind: resource.kind.clone(),
+                    attributes,
+                    relationships: resource.relationships.clone(),
+                });
+            } else {
+                included.push(resource.clone());
+            }
+        }
+        Ok(Document { data, included })
+    }
+}
+
+/// Parses the JSON data of a stream event
+fn parse_event<T: serde::de::DeserializeOwned>(event: &ServerEvent) -> Result<T, ApiError> {
+    serde_json::from_str(&event.data).map_err(|source| ApiError::MalformedEvent {
+        event: event.event.clone(),
+        source,
+    })
+}
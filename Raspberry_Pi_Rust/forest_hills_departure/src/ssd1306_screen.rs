@@ -1,25 +1,58 @@
 extern crate chrono;
+extern crate chrono_tz;
 extern crate embedded_graphics;
 extern crate rppal; // Crate for SPI, I2C, and GPIO on raspberry pi
 extern crate ssd1306; // Crate for current I2C oled display
 
-use chrono::{DateTime, Local};
+use crate::train_time::{Departure, EndpointHealth};
+use chrono::DateTime;
+use chrono_tz::Tz;
 use embedded_graphics::{
-    fonts::{Font12x16, Text},
+    fonts::{Font12x16, Font6x12, Font6x8, Text},
     pixelcolor::BinaryColor,
     prelude::*,
-    style::TextStyleBuilder,
+    primitives::Rectangle,
+    style::{PrimitiveStyle, TextStyleBuilder},
 };
 use rppal::i2c;
 use ssd1306::{prelude::*, Builder, I2CDIBuilder};
 
+/// Number of 6 pixel wide characters that fit across the screen
+const BANNER_CHARS: usize = 21;
+
+/// Top of the alert banner, below the two train times
+const BANNER_Y: i32 = 52;
+
+/// Left of the "CANCELLED" label, two pixels after a marked time drawn from the left edge
+const CANCELLED_X: i32 = 74;
+
+/// Left of a time after the "first train" label, one pixel after the eleven label characters
+const FIRST_TRAIN_X: i32 = 67;
+
+/// Left of the occupancy gauge at the right edge of a train's line
+const OCCUPANCY_X: i32 = 123;
+
+/// Top of the status line between the train times and the banner, which shows where the next
+/// train is, the destination or a warning about out of date or partly retrieved departures
+const STATUS_Y: i32 = 43;
+
 /// Structure that contains screen information
 pub struct ScreenDisplay {
     display: GraphicsMode<I2CInterface<i2c::I2c>>,
-    // the closest train time
-    train1: Option<DateTime<Local>>,
-    // the second closest train time
-    train2: Option<DateTime<Local>>,
+    // the closest train
+    train1: Option<Departure>,
+    // the second closest train
+    train2: Option<Departure>,
+    // the alerts scrolling across the banner, padded so they scroll in from the right
+    banner: Option<Vec<char>>,
+    // index of the first banner character on screen
+    banner_offset: usize,
+    // the warning shown while the departures are out of date
+    stale_text: Option<String>,
+    // the warning shown while the predictions or the schedules can not be retrieved
+    health_text: Option<String>,
+    // where the trains are headed, shown on the status line while the departures are current
+    destination: Option<String>,
 }
 
 // functions to initialize and change screen display
@@ -39,26 +72,32 @@ impl ScreenDisplay {
             display: disp,
             train1: None,
             train2: None,
+            banner: None,
+            banner_offset: 0,
+            stale_text: None,
+            health_text: None,
+            destination: None,
         })
     }
 
-    /// Displays train1 and train2 on the screen display
+    /// Displays train1 and train2 on the screen display.  Times from the timetable instead of a
+    /// realtime prediction are marked with a leading "~"
     pub fn display_trains(
         &mut self,
-        train_times: &Vec<DateTime<Local>>,
+        departures: &[Departure],
     ) -> Result<(), Box<dyn std::error::Error>> {
         // create a variable to test whether or not the screen needs to be updated
         let mut update_screen = false;
         // if train1 is different than nearest train, replace with nearest train and update later
-        if Some(train_times[0]) != self.train1 {
-            self.train1 = Some(train_times[0].clone());
+        if Some(&departures[0]) != self.train1.as_ref() {
+            self.train1 = Some(departures[0].clone());
             update_screen = true;
         }
         // if there is more than one train time, proceed with train2
-        if train_times.len() > 1 {
+        if departures.len() > 1 {
             // if train 2 is different from second train, replace and update
-            if Some(train_times[1]) != self.train2 {
-                self.train2 = Some(train_times[1].clone());
+            if Some(&departures[1]) != self.train2.as_ref() {
+                self.train2 = Some(departures[1].clone());
                 update_screen = true;
             }
         } else {
@@ -68,44 +107,303 @@ impl ScreenDisplay {
         // if train times were different than what's on the display, update display
         if update_screen {
             self.clear_display(false)?;
-            // create a new text style for the screen with chosen font
-            let text_style = TextStyleBuilder::new(Font12x16)
-                .text_color(BinaryColor::On)
-                .build();
             // if there is a train1, display train time
-            if let Some(train1) = self.train1 {
-                let time = train1.format("%H:%M").to_string();
-                // creates text buffer
-                Text::new(&time, Point::new(35, 5))
-                    .into_styled(text_style)
-                    .draw(&mut self.display).unwrap();
-                // displays text buffer
-                self.display.flush().unwrap();
+            if let Some(train1) = self.train1.clone() {
+                self.draw_departure(&train1, 5)?;
             }
             // if there is a train2, display train time
-            if let Some(train2) = self.train2 {
-                let time = train2.format("%H:%M").to_string();
-                // creats text buffer
-                Text::new(&time, Point::new(35, 25))
-                    .into_styled(text_style)
-                    .draw(&mut self.display).unwrap();
-                // displays text buffer
+            if let Some(train2) = self.train2.clone() {
+                self.draw_departure(&train2, 25)?;
+            }
+            // clearing the display also removed the status line
+            self.draw_status()?;
+            self.display.flush().unwrap();
+        }
+        Ok(())
+    }
+
+    /// Draws a departure time with its top at y and pushes it to the screen.  Cancelled trains
+    /// have "CANCELLED" after the time.  Trains with a known arrival at the destination show both
+    /// times in a smaller font instead
+    fn draw_departure(&mut self, departure: &Departure, y: i32) -> Result<(), Box<dyn std::error::Error>> {
+        let occupancy = occupancy_percentage(departure);
+        if let Some(percentage) = occupancy {
+            self.draw_occupancy(percentage, y)?;
+        }
+        if let Some(trip) = trip_text(departure, occupancy.is_some()) {
+            let trip_style = TextStyleBuilder::new(Font6x12)
+                .text_color(BinaryColor::On)
+                .build();
+            // vertically centered on the 16 pixel high line
+            Text::new(&trip, Point::new(0, y + 2))
+                .into_styled(trip_style)
+                .draw(&mut self.display)
+                .unwrap();
+            self.display.flush().unwrap();
+            return Ok(());
+        }
+        // create a new text style for the screen with chosen font
+        let text_style = TextStyleBuilder::new(Font12x16)
+            .text_color(BinaryColor::On)
+            .build();
+        let (time, x) = departure_text(departure);
+        // creates text buffer
+        Text::new(&time, Point::new(x, y))
+            .into_styled(text_style)
+            .draw(&mut self.display)
+            .unwrap();
+        let label = if departure.cancelled {
+            Some(("CANCELLED", CANCELLED_X))
+        } else if departure.next_service_day {
+            Some(("first train", 0))
+        } else {
+            None
+        };
+        if let Some((label, label_x)) = label {
+            let label_style = TextStyleBuilder::new(Font6x8)
+                .text_color(BinaryColor::On)
+                .build();
+            // vertically centered on the 16 pixel high time
+            Text::new(label, Point::new(label_x, y + 4))
+                .into_styled(label_style)
+                .draw(&mut self.display)
+                .unwrap();
+        }
+        // displays text buffer
+        self.display.flush().unwrap();
+        Ok(())
+    }
+
+    /// Draws a gauge 5 pixels wide and as high as a train's line at the right edge, filled from
+    /// the bottom as far as the train is full
+    fn draw_occupancy(&mut self, percentage: u8, y: i32) -> Result<(), Box<dyn std::error::Error>> {
+        Rectangle::new(Point::new(OCCUPANCY_X, y), Point::new(127, y + 15))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(&mut self.display)
+            .unwrap();
+        // 14 rows inside the outline, at least one is filled so the gauge never looks blank
+        let rows = ((14 * i32::from(percentage.min(100)) + 99) / 100).max(1);
+        Rectangle::new(Point::new(OCCUPANCY_X + 1, y + 15 - rows), Point::new(126, y + 14))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(&mut self.display)
+            .unwrap();
+        Ok(())
+    }
+
+    /// Shows "no data since HH:MM" below the train times while the departures are out of date,
+    /// with the time they were last retrieved at in the agency's time zone, and removes it once
+    /// they are current again
+    pub fn display_stale(
+        &mut self,
+        stale: bool,
+        fetched_at: Option<DateTime<Tz>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let stale_text = match (stale, fetched_at) {
+            (false, _) => None,
+            (true, Some(fetched_at)) => Some(format!("no data since {}", fetched_at.format("%H:%M"))),
+            (true, None) => Some("no data".to_string()),
+        };
+        if stale_text == self.stale_text {
+            return Ok(());
+        }
+        self.stale_text = stale_text;
+        self.draw_status()?;
+        self.display.flush().unwrap();
+        Ok(())
+    }
+
+    /// Shows "predictions down" or "schedules down" on the status line while the departures are
+    /// merged without them, unless the departures are out of date, and removes it once both are
+    /// retrieved again
+    pub fn display_health(
+        &mut self,
+        predictions: &EndpointHealth,
+        schedules: &EndpointHealth,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let health_text = if !predictions.is_ok() {
+            Some("predictions down".to_string())
+        } else if !schedules.is_ok() {
+            Some("schedules down".to_string())
+        } else {
+            None
+        };
+        if health_text == self.health_text {
+            return Ok(());
+        }
+        self.health_text = health_text;
+        self.draw_status()?;
+        self.display.flush().unwrap();
+        Ok(())
+    }
+
+    /// Shows "to " and the destination of the trains on the status line, e.g. "to Oak Grove",
+    /// whenever the departures are current and complete and it is not known where the next train
+    /// is
+    pub fn display_destination(
+        &mut self,
+        destination: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.destination = destination;
+        self.draw_status()?;
+        self.display.flush().unwrap();
+        Ok(())
+    }
+
+    /// Draws the stale data warning, or otherwise the predictions or schedules being down, or
+    /// otherwise where the next train is, or otherwise the destination, into the display buffer.
+    /// The line is blanked if there is none of them
+    fn draw_status(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Rectangle::new(Point::new(0, STATUS_Y), Point::new(127, STATUS_Y + 7))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+            .draw(&mut self.display)
+            .unwrap();
+        let vehicle_text = self
+            .train1
+            .as_ref()
+            .and_then(|train1| train1.vehicle.as_ref())
+            .and_then(|vehicle| vehicle.text());
+        let destination_text = self
+            .destination
+            .as_ref()
+            .map(|destination| format!("to {}", destination));
+        let status_text = self
+            .stale_text
+            .clone()
+            .or_else(|| self.health_text.clone())
+            .or(vehicle_text)
+            .or(destination_text)
+            .map(|status_text| status_text.chars().take(BANNER_CHARS).collect::<String>());
+        if let Some(status_text) = status_text {
+            let text_style = TextStyleBuilder::new(Font6x8)
+                .text_color(BinaryColor::On)
+                .build();
+            // centered, each character is 6 pixels wide
+            let x = (128 - 6 * status_text.chars().count() as i32) / 2;
+            Text::new(&status_text, Point::new(x, STATUS_Y))
+                .into_styled(text_style)
+                .draw(&mut self.display)
+                .unwrap();
+        }
+        Ok(())
+    }
+
+    /// Scrolls the alerts across a banner below the train times by one character per call.  The
+    /// banner is cleared if there are no alerts
+    pub fn display_alerts(&mut self, alerts: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        if alerts.is_empty() {
+            if self.banner.take().is_some() {
+                self.clear_banner()?;
                 self.display.flush().unwrap();
             }
+            return Ok(());
         }
+        let text = format!("{}{}", " ".repeat(BANNER_CHARS), alerts.join("  +++  "));
+        let banner: Vec<char> = text.chars().collect();
+        // start from the beginning when the alerts change, otherwise scroll one character
+        if Some(&banner) != self.banner.as_ref() {
+            self.banner = Some(banner);
+            self.banner_offset = 0;
+        } else {
+            self.banner_offset += 1;
+        }
+        let banner = self.banner.as_ref().unwrap();
+        if self.banner_offset >= banner.len() {
+            self.banner_offset = 0;
+        }
+        // only draw the characters that are on screen, since pixels drawn past the right edge
+        // wrap around onto the next rows
+        let visible: String = banner
+            .iter()
+            .cycle()
+            .skip(self.banner_offset)
+            .take(BANNER_CHARS)
+            .collect();
+        self.clear_banner()?;
+        let text_style = TextStyleBuilder::new(Font6x8)
+            .text_color(BinaryColor::On)
+            .build();
+        Text::new(&visible, Point::new(0, BANNER_Y))
+            .into_styled(text_style)
+            .draw(&mut self.display)
+            .unwrap();
+        self.display.flush().unwrap();
         Ok(())
     }
 
-    /// Function to clear screen display
+    /// Blanks the banner area in the display buffer
+    fn clear_banner(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Rectangle::new(Point::new(0, BANNER_Y), Point::new(127, BANNER_Y + 7))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+            .draw(&mut self.display)
+            .unwrap();
+        Ok(())
+    }
+
+    /// Function to clear screen display.  With reset_trains the trains are forgotten so they are
+    /// drawn again once there are some, while the status line stays to say why there are none
     pub fn clear_display(&mut self, reset_trains: bool) -> Result<(), Box<dyn std::error::Error>> {
+        // clears the buffer
+        self.display.clear();
         if reset_trains {
             self.train1 = None;
             self.train2 = None;
+            self.draw_status()?;
         }
-        // clears the buffer
-        self.display.clear();
         // sends cleared buffer to screen to refresh
         self.display.flush().unwrap();
         Ok(())
     }
 }
+
+/// Formats the departure time along with the x position that keeps the time itself in place
+/// whether or not it is marked as timetabled.  Cancelled times move to the left edge and the next
+/// service day's first train moves to the right to make room for their labels
+fn departure_text(departure: &Departure) -> (String, i32) {
+    let time = departure.time.format("%H:%M");
+    if departure.cancelled {
+        (format!("~{}", time), 0)
+    } else if departure.next_service_day {
+        // the first train of tomorrow is always timetabled, the label replaces the marker
+        (time.to_string(), FIRST_TRAIN_X)
+    } else if departure.is_predicted() {
+        (time.to_string(), 35)
+    } else {
+        // the marker is one 12 pixel wide character to the left of the time
+        (format!("~{}", time), 23)
+    }
+}
+
+/// Formats the departure and the arrival at the destination as "dep 08:14 > arr 08:31", None if
+/// the arrival is not known.  Fills the 21 characters across the screen, so timetabled times put
+/// their "~" in place of the space, cancelled trains replace the arrival with "CANCELLED" and
+/// the next service day's first train starts with "1st".  Next to an occupancy gauge the arrow
+/// loses its spaces to make room
+fn trip_text(departure: &Departure, occupancy: bool) -> Option<String> {
+    let arrival = departure.destination_time?.format("%H:%M");
+    let time = departure.time.format("%H:%M");
+    let arrow = if occupancy { ">" } else { " > " };
+    let text = if departure.cancelled {
+        format!("dep~{}  CANCELLED", time)
+    } else if departure.next_service_day {
+        format!("1st {}{}arr {}", time, arrow, arrival)
+    } else if departure.is_predicted() {
+        format!("dep {}{}arr {}", time, arrow, arrival)
+    } else {
+        format!("dep~{}{}arr {}", time, arrow, arrival)
+    };
+    Some(text)
+}
+
+/// How full the train of a departure is, 0 to 100, if its vehicle reports it.  Cancelled trains
+/// and the next service day's first train have their label where the gauge would go
+fn occupancy_percentage(departure: &Departure) -> Option<u8> {
+    if departure.cancelled || departure.next_service_day {
+        return None;
+    }
+    departure
+        .vehicle
+        .as_ref()?
+        .occupancy
+        .as_ref()?
+        .percentage()
+}
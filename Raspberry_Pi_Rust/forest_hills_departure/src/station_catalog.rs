@@ -0,0 +1,259 @@
+extern crate serde;
+extern crate serde_json;
+extern crate std;
+
+use crate::atomic_file;
+use crate::mbta_api::{Document, MbtaClient, Resource, Route, Stop};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::{fs, path::Path};
+
+/// MBTA route types of the stations in the catalog: light rail, subway, commuter rail and ferry.
+/// Bus stops are left out, there are thousands of them
+pub const STATION_ROUTE_TYPES: [u8; 4] = [0, 1, 2, 4];
+
+/// File the refreshed station catalog is kept in unless another is configured
+pub const DEFAULT_STATIONS_PATH: &str = "/var/tmp/forest_hills_stations.json";
+
+/// Catalog shipped with the board, used until the stations are refreshed from the MBTA API
+const BUNDLED_STATIONS: &str = include_str!("../stations.json");
+
+/// What kind of stop a stop id stands for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopKind {
+    /// A station grouping platforms, e.g. place-forhl
+    ParentStation,
+    /// A platform within a station, e.g. 70001 for the Orange Line at Forest Hills
+    Platform,
+    /// A bus stop, which has no parent station, e.g. 10642
+    BusStop,
+    /// A ferry pier, e.g. Boat-Long
+    FerryPier,
+}
+
+impl StopKind {
+    /// Works out the kind of a stop from its attributes and whether it has a parent station.
+    /// Every rail platform belongs to a station, so other stops without one are bus stops
+    pub fn of(id: &str, stop: &Stop, has_parent: bool) -> StopKind {
+        if stop.location_type == 1 {
+            StopKind::ParentStation
+        } else if id.starts_with("Boat-") || stop.vehicle_type == Some(4) {
+            StopKind::FerryPier
+        } else if has_parent {
+            StopKind::Platform
+        } else {
+            StopKind::BusStop
+        }
+    }
+}
+
+/// A station, or a single stop, and the routes serving it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Station {
+    // stop id as used in the departure queries, e.g. place-forhl, 10642 or Boat-Long
+    pub id: String,
+    pub name: String,
+    pub kind: StopKind,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    // ids of the routes serving the station, sorted
+    pub routes: Vec<String>,
+    // MBTA route types of those routes, sorted, e.g. 1 for subway or 3 for bus
+    #[serde(default)]
+    pub route_types: Vec<u8>,
+}
+
+impl Station {
+    /// The station name as typed on the command line, with underscores for spaces and without
+    /// apostrophes, e.g. Forest_Hills
+    pub fn argument_name(&self) -> String {
+        self.name.replace(' ', "_").replace('\'', "")
+    }
+}
+
+/// The stations of the MBTA, sorted by name
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StationCatalog {
+    pub stations: Vec<Station>,
+}
+
+impl StationCatalog {
+    /// The catalog shipped with the board
+    pub fn bundled() -> Result<StationCatalog, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_str(BUNDLED_STATIONS)?)
+    }
+
+    /// The catalog refreshed into path if there is one, otherwise the bundled catalog.  Needs no
+    /// network
+    pub fn local(path: &Path) -> Result<StationCatalog, Box<dyn std::error::Error>> {
+        if path.exists() {
+            StationCatalog::load(path)
+        } else {
+            StationCatalog::bundled()
+        }
+    }
+
+    /// Reads a catalog written by save
+    pub fn load(path: &Path) -> Result<StationCatalog, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("could not read stations {}: {}", path.display(), err))?;
+        let catalog = serde_json::from_str(&text)
+            .map_err(|err| format!("malformed stations {}: {}", path.display(), err))?;
+        Ok(catalog)
+    }
+
+    /// Writes the catalog to a file, leaving the previous catalog intact if the write fails
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        atomic_file::write(path, &serde_json::to_vec_pretty(self)?, "stations")
+    }
+
+    /// Retrieves the stations of the route types from the MBTA API, with the routes serving each.
+    /// Takes one request for the stops, one for the routes and one for the stops of each route
+    pub fn fetch(
+        client: &MbtaClient,
+        route_types: &[u8],
+    ) -> Result<StationCatalog, Box<dyn std::error::Error>> {
+        let route_types = route_types
+            .iter()
+            .map(|route_type| route_type.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        let stops = client.get::<Stop>(&format!(
+            "/stops?filter[route_type]={}&include=parent_station",
+            route_types
+        ))?;
+        let mut catalog = StationCatalog::from_stops(&stops)?;
+        let routes = client.get::<Route>(&format!("/routes?filter[type]={}", route_types))?;
+        for route in &routes.data {
+            let route_stops = client.get::<Stop>(&format!("/stops?filter[route]={}", route.id))?;
+            catalog.add_route(route, &stops, &route_stops);
+        }
+        Ok(catalog)
+    }
+
+    /// Retrieves a single stop of any kind by its id, e.g. a bus stop left out of the catalog,
+    /// with the routes serving it.  Returns None if the MBTA has no such stop
+    pub fn fetch_stop(
+        client: &MbtaClient,
+        stop_id: &str,
+    ) -> Result<Option<Station>, Box<dyn std::error::Error>> {
+        let stops = client.get::<Stop>(&format!("/stops?filter[id]={}", stop_id))?;
+        let stop = match stops.data.first() {
+            Some(stop) => stop,
+            None => return Ok(None),
+        };
+        let has_parent = stop.related_id("parent_station").is_some();
+        let kind = StopKind::of(&stop.id, &stop.attributes, has_parent);
+        let mut station = new_station(&stop.id, &stop.attributes, kind);
+        let routes = client.get::<Route>(&format!("/routes?filter[stop]={}", stop_id))?;
+        for route in &routes.data {
+            add_route_to(&mut station, route);
+        }
+        Ok(Some(station))
+    }
+
+    /// Adds a station to the catalog, replacing any station with the same id
+    pub fn insert(&mut self, station: Station) {
+        self.stations.retain(|existing| existing.id != station.id);
+        self.stations.push(station);
+        self.stations.sort_by(|first, second| {
+            first.name.cmp(&second.name).then(first.id.cmp(&second.id))
+        });
+    }
+
+    /// Builds the catalog from a document of stops, without any routes yet.  Platforms are
+    /// replaced by their parent station from the included resources
+    pub fn from_stops(stops: &Document<Stop>) -> Result<StationCatalog, Box<dyn std::error::Error>> {
+        let mut stations: HashMap<String, Station> = HashMap::new();
+        for stop in &stops.data {
+            let station = match stops.related::<Stop>(stop, "parent_station")? {
+                Some(parent) => {
+                    new_station(&parent.id, &parent.attributes, StopKind::ParentStation)
+                }
+                None => {
+                    let kind = StopKind::of(&stop.id, &stop.attributes, false);
+                    new_station(&stop.id, &stop.attributes, kind)
+                }
+            };
+            stations.entry(station.id.clone()).or_insert(station);
+        }
+        let mut stations: Vec<Station> = stations.into_values().collect();
+        stations.sort_by(|first, second| {
+            first.name.cmp(&second.name).then(first.id.cmp(&second.id))
+        });
+        Ok(StationCatalog { stations })
+    }
+
+    /// Adds the route to the stations of its stops.  Stops that are platforms are looked up in
+    /// the catalog's stops document to find their station
+    pub fn add_route(
+        &mut self,
+        route: &Resource<Route>,
+        stops: &Document<Stop>,
+        route_stops: &Document<Stop>,
+    ) {
+        let station_ids: BTreeSet<&str> = route_stops
+            .data
+            .iter()
+            .map(|route_stop| {
+                stops
+                    .data
+                    .iter()
+                    .find(|stop| stop.id == route_stop.id)
+                    .unwrap_or(route_stop)
+                    .related_id("parent_station")
+                    .unwrap_or(&route_stop.id)
+            })
+            .collect();
+        for station in self.stations.iter_mut() {
+            if station_ids.contains(station.id.as_str()) {
+                add_route_to(station, route);
+            }
+        }
+    }
+
+    /// Finds a station by its id, its id without the place- prefix, its name or its command line
+    /// name
+    pub fn find(&self, station: &str) -> Option<&Station> {
+        self.stations.iter().find(|candidate| {
+            candidate.id == station
+                || candidate.id.strip_prefix("place-") == Some(station)
+                || candidate.name == station
+                || candidate.argument_name() == station
+        })
+    }
+
+    /// The command line names of all stations, sorted
+    pub fn argument_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.stations.iter().map(Station::argument_name).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// Creates a station without routes from a stop
+fn new_station(id: &str, stop: &Stop, kind: StopKind) -> Station {
+    Station {
+        id: id.to_string(),
+        name: stop.name.clone(),
+        kind,
+        latitude: stop.latitude,
+        longitude: stop.longitude,
+        routes: Vec::new(),
+        route_types: Vec::new(),
+    }
+}
+
+/// Adds a route and its route type to a station, keeping both lists sorted without repeats
+fn add_route_to(station: &mut Station, route: &Resource<Route>) {
+    if !station.routes.contains(&route.id) {
+        station.routes.push(route.id.clone());
+        station.routes.sort();
+    }
+    if !station.route_types.contains(&route.attributes.route_type) {
+        station.route_types.push(route.attributes.route_type);
+        station.route_types.sort();
+    }
+}
@@ -1,108 +1,970 @@
 extern crate chrono;
-extern crate reqwest;
-extern crate serde_json;
+extern crate chrono_tz;
+extern crate serde;
 extern crate std;
 
+use crate::agency_time;
+use crate::gtfs_static::GtfsFeed;
+use crate::mbta_api::{
+    Carriage, Document, MbtaClient, OccupancyStatus, Prediction, Resource, ResourceType, Route,
+    Schedule, Stop, Trip, Vehicle, VehicleStopStatus,
+};
+use crate::prediction_stream::{EventReader, PredictionSet};
 use chrono::prelude::*;
-use chrono::{DateTime, Local, TimeZone};
-use serde_json::Value;
-use std::collections::HashMap;
+use chrono::{DateTime, Duration, FixedOffset};
+use chrono_tz::Tz;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 
-/// Main function to retrieve train times from Forest Hills Station for inbound commuter rail
-pub fn train_times(dir_code: &str, station: &str) -> Result<Option<Vec<DateTime<Local>>>, Box<dyn std::error::Error>> {
-    // get prediction times
-    let prediction_times = get_prediction_times(station, dir_code)?;
-    // get schuduled times, if None, create empty hashmap
-    let mut scheduled_times = get_scheduled_times(station, dir_code)?.unwrap_or(HashMap::new());
-    // if there are predicted times, replace the scheduled times with the more accurate predicted
-    // tiem
-    if let Some(pred_times) = prediction_times {
-        for key in pred_times.keys() {
-            if scheduled_times.keys().any(|schud_key| schud_key == key) {
-                *scheduled_times.get_mut(key).unwrap() = pred_times[key]
-            } else {
-                scheduled_times.insert(key.clone(), pred_times[key]);
+/// Whether a departure time is a realtime prediction or only the timetabled time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    Predicted,
+    Scheduled,
+}
+
+/// Which of a train's times at the station is counted down to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeEvent {
+    /// When the train leaves, for catching it
+    #[default]
+    Departure,
+    /// When the train gets in, for meeting someone off it
+    Arrival,
+}
+
+impl TimeEvent {
+    /// Picks the time of the event out of a train's arrival and departure times.  Falls back to
+    /// the other time for stops that only have one, like the first and last stops of a trip
+    pub fn pick<T>(self, arrival: Option<T>, departure: Option<T>) -> Option<T> {
+        match self {
+            TimeEvent::Departure => departure.or(arrival),
+            TimeEvent::Arrival => arrival.or(departure),
+        }
+    }
+}
+
+impl FromStr for TimeEvent {
+    type Err = String;
+
+    fn from_str(event: &str) -> Result<Self, Self::Err> {
+        match event {
+            "departure" => Ok(TimeEvent::Departure),
+            "arrival" => Ok(TimeEvent::Arrival),
+            _ => Err(format!("unknown time event: {}", event)),
+        }
+    }
+}
+
+/// A direction of travel along a route, e.g. Inbound to South Station or North to Oak Grove
+#[derive(Debug, Clone, PartialEq)]
+pub struct Direction {
+    // direction id used in the API, 0 or 1
+    pub id: u8,
+    pub name: String,
+    pub destination: String,
+}
+
+impl Direction {
+    /// Whether the input names the direction: its name or destination in any case, with
+    /// underscores for spaces, or its direction id
+    pub fn matches(&self, input: &str) -> bool {
+        let input = input.replace('_', " ").to_lowercase();
+        input == self.name.to_lowercase()
+            || input == self.destination.to_lowercase()
+            || input == self.id.to_string()
+    }
+}
+
+/// Reads the directions of a route from its direction names and destinations.  Directions the
+/// route does not run in are null in the API and left out
+pub fn route_directions(route: &Route) -> Vec<Direction> {
+    route
+        .direction_names
+        .iter()
+        .zip(route.direction_destinations.iter())
+        .enumerate()
+        .filter_map(|(id, (name, destination))| {
+            Some(Direction {
+                id: id as u8,
+                name: name.clone()?,
+                destination: destination.clone()?,
+            })
+        })
+        .collect()
+}
+
+/// A single train departing from the station
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Departure {
+    pub trip_id: String,
+    pub route_id: String,
+    pub headsign: Option<String>,
+    pub direction_id: u8,
+    // in the agency's time zone
+    #[serde(with = "agency_time")]
+    pub time: DateTime<Tz>,
+    pub source: TimeSource,
+    // prediction status text, e.g. "Delayed" or "All aboard", if the MBTA gave one
+    pub status: Option<String>,
+    pub stop_sequence: Option<u32>,
+    // the trip was cancelled or will not stop at the station, time is the scheduled time
+    #[serde(default)]
+    pub cancelled: bool,
+    // the train runs on the next service day, shown because today's service is over
+    #[serde(default)]
+    pub next_service_day: bool,
+    // when the train gets to the destination the trips are filtered on, if it is known
+    #[serde(default, with = "agency_time::optional")]
+    pub destination_time: Option<DateTime<Tz>>,
+    // where the train is, for the next predicted trains whose vehicle the MBTA knows
+    #[serde(default)]
+    pub vehicle: Option<VehicleStatus>,
+}
+
+impl Departure {
+    /// Whether the departure time comes from a realtime prediction
+    pub fn is_predicted(&self) -> bool {
+        self.source == TimeSource::Predicted
+    }
+}
+
+/// Where the train running a trip is, relative to the station
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VehicleStatus {
+    pub current_status: VehicleStopStatus,
+    // name of the stop the status is about, e.g. Roslindale Village
+    pub stop_name: Option<String>,
+    // stops the train still has to reach before it is at the station, 0 once it is there
+    pub stops_away: Option<u32>,
+    // how full the train is, if the vehicle reports it
+    #[serde(default)]
+    pub occupancy: Option<Occupancy>,
+}
+
+/// How full a train is
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Occupancy {
+    // for the whole train
+    pub status: Option<OccupancyStatus>,
+    // from the front of the train, on lines that report each carriage, e.g. the Orange Line
+    pub carriages: Vec<Carriage>,
+}
+
+impl Occupancy {
+    /// How full the train is, 0 to 100.  The average of the carriages' percentages if they report
+    /// them, otherwise estimated from the train's status or else the carriages' statuses.  None
+    /// if nothing is known
+    pub fn percentage(&self) -> Option<u8> {
+        let average = |percentages: Vec<u8>| {
+            if percentages.is_empty() {
+                return None;
             }
+            let total: u32 = percentages.iter().map(|percentage| u32::from(*percentage)).sum();
+            Some((total / percentages.len() as u32) as u8)
+        };
+        average(
+            self.carriages
+                .iter()
+                .filter_map(|carriage| carriage.occupancy_percentage)
+                .collect(),
+        )
+        .or_else(|| self.status.and_then(status_percentage))
+        .or_else(|| {
+            average(
+                self.carriages
+                    .iter()
+                    .filter_map(|carriage| carriage.occupancy_status.and_then(status_percentage))
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// A rough percentage for an occupancy status, None if the status says nothing about it
+fn status_percentage(status: OccupancyStatus) -> Option<u8> {
+    match status {
+        OccupancyStatus::Empty => Some(0),
+        OccupancyStatus::ManySeatsAvailable => Some(25),
+        OccupancyStatus::FewSeatsAvailable => Some(50),
+        OccupancyStatus::StandingRoomOnly => Some(75),
+        OccupancyStatus::CrushedStandingRoomOnly
+        | OccupancyStatus::Full
+        | OccupancyStatus::NotAcceptingPassengers => Some(100),
+        OccupancyStatus::NoDataAvailable | OccupancyStatus::NotBoardable => None,
+    }
+}
+
+impl VehicleStatus {
+    /// Describes where the train is, e.g. "at Roslindale Village" or "3 stops away".  None if
+    /// there is too little to go on
+    pub fn text(&self) -> Option<String> {
+        match (self.current_status, &self.stop_name, self.stops_away) {
+            (VehicleStopStatus::StoppedAt, Some(stop_name), _) => Some(format!("at {}", stop_name)),
+            (_, _, Some(0)) => Some("at the station".to_string()),
+            (_, _, Some(1)) => Some("1 stop away".to_string()),
+            (_, _, Some(stops_away)) => Some(format!("{} stops away", stops_away)),
+            (_, Some(stop_name), None) => Some(format!("on the way to {}", stop_name)),
+            (_, None, None) => None,
+        }
+    }
+}
+
+/// Trip id to where the train running it is
+pub type VehicleStatuses = HashMap<String, VehicleStatus>;
+
+/// Trip id to departure, as returned by the MBTA API
+pub type TripDepartures = HashMap<String, Departure>;
+
+/// A trip's stop at the destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct DestinationStop {
+    pub stop_sequence: u32,
+    // predicted arrival at the destination, or the scheduled one without a prediction
+    pub arrival: Option<DateTime<Tz>>,
+}
+
+/// Trip id to the trip's stop at the destination
+pub type DestinationStops = HashMap<String, DestinationStop>;
+
+/// Which of the trips in the direction are shown.  The default shows every trip
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TripFilter {
+    // stop id the trips must let passengers off at after the station, e.g. place-rugg
+    pub destination: Option<String>,
+    // only trips whose headsign contains one of these are shown, every trip if empty
+    pub include_headsigns: Vec<String>,
+    // trips whose headsign contains one of these are left out
+    pub exclude_headsigns: Vec<String>,
+}
+
+impl TripFilter {
+    /// Keeps the departures the filter lets through, with their arrival at the destination.  None
+    /// if there are none left.  destination_stops are the trips calling at the destination,
+    /// without them no trips are left out for the destination
+    pub fn apply(
+        &self,
+        departures: Option<Vec<Departure>>,
+        destination_stops: Option<&DestinationStops>,
+    ) -> Option<Vec<Departure>> {
+        let departures: Vec<Departure> = departures?
+            .into_iter()
+            .filter(|departure| self.keeps(departure, destination_stops))
+            .map(|mut departure| {
+                departure.destination_time = destination_stops
+                    .and_then(|stops| stops.get(&departure.trip_id))
+                    .and_then(|stop| stop.arrival);
+                departure
+            })
+            .collect();
+        if departures.is_empty() {
+            return None;
         }
+        Some(departures)
     }
-    // get the current time and filter out any train time before now
-    let now = Local::now();
-    let mut all_times = scheduled_times
-        .values()
-        .filter_map(|date| {
-            if date > &now {
-                Some(date.clone())
-            } else {
-                None
+
+    /// Whether the departure's headsign passes the headsign filters and its trip calls at the
+    /// destination after the station.  Headsigns are compared in any case, with underscores for
+    /// spaces
+    fn keeps(&self, departure: &Departure, destination_stops: Option<&DestinationStops>) -> bool {
+        let headsign = departure.headsign.as_deref().unwrap_or_default().to_lowercase();
+        let named = |filters: &[String]| {
+            filters
+                .iter()
+                .any(|filter| headsign.contains(&filter.replace('_', " ").to_lowercase()))
+        };
+        if !self.include_headsigns.is_empty() && !named(&self.include_headsigns) {
+            return false;
+        }
+        if named(&self.exclude_headsigns) {
+            return false;
+        }
+        match (destination_stops, departure.stop_sequence) {
+            (Some(stops), Some(stop_sequence)) => stops
+                .get(&departure.trip_id)
+                .is_some_and(|stop| stop.stop_sequence > stop_sequence),
+            (Some(stops), None) => stops.contains_key(&departure.trip_id),
+            (None, _) => true,
+        }
+    }
+}
+
+/// The station, direction and routes to retrieve departures for, which of their trips to show,
+/// whether to use the trains' departure or arrival times, and the time zone to give them in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DepartureQuery {
+    // stop id of a station, platform, bus stop or ferry pier, e.g. place-forhl, 70001, 10642 or
+    // Boat-Long
+    pub station: String,
+    pub dir_code: String,
+    pub routes: Vec<String>,
+    // the destination and headsigns of the trips to show.  Only the MBTA source knows which
+    // trips call at the destination, the other sources filter on the headsigns only
+    pub filter: TripFilter,
+    pub event: TimeEvent,
+    // the agency's time zone, used for the times and service days whatever the system's is
+    pub time_zone: Tz,
+}
+
+/// Predicted departures, and the trips the predictions say are cancelled or skip the station
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Predictions {
+    pub departures: TripDepartures,
+    pub cancelled: HashSet<String>,
+}
+
+/// Whether one of the requests departures are merged from succeeded
+#[derive(Debug, Clone, PartialEq)]
+pub enum EndpointHealth {
+    Ok,
+    // the request failed with the error message
+    Failed(String),
+}
+
+impl EndpointHealth {
+    pub fn is_ok(&self) -> bool {
+        *self == EndpointHealth::Ok
+    }
+}
+
+/// Departures merged from whichever of the predictions and schedules requests succeeded, with
+/// how each request went
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedDepartures {
+    pub departures: Option<Vec<Departure>>,
+    pub predictions: EndpointHealth,
+    pub schedules: EndpointHealth,
+}
+
+impl MergedDepartures {
+    /// Departures from requests that all succeeded
+    pub fn healthy(departures: Option<Vec<Departure>>) -> MergedDepartures {
+        MergedDepartures {
+            departures,
+            predictions: EndpointHealth::Ok,
+            schedules: EndpointHealth::Ok,
+        }
+    }
+
+    /// Merges the predicted and scheduled departures that were retrieved, leaving out the side
+    /// that failed.  Fails with the predictions error only if both sides failed
+    pub fn merge(
+        predicted: Result<Predictions, Box<dyn std::error::Error>>,
+        scheduled: Result<TripDepartures, Box<dyn std::error::Error>>,
+        now: DateTime<Tz>,
+    ) -> Result<MergedDepartures, Box<dyn std::error::Error>> {
+        let (predicted, predictions) = match predicted {
+            Ok(predicted) => (predicted, EndpointHealth::Ok),
+            Err(err) => {
+                if let Err(schedules_err) = &scheduled {
+                    warn!("could not retrieve schedules either: {}", schedules_err);
+                    return Err(err);
+                }
+                (Predictions::default(), EndpointHealth::Failed(err.to_string()))
             }
+        };
+        let (scheduled, schedules) = match scheduled {
+            Ok(scheduled) => (scheduled, EndpointHealth::Ok),
+            Err(err) => (TripDepartures::new(), EndpointHealth::Failed(err.to_string())),
+        };
+        Ok(MergedDepartures {
+            departures: merge_departures(predicted, scheduled, now),
+            predictions,
+            schedules,
+        })
+    }
+}
+
+/// Minutes between schedule refreshes while streaming predictions
+pub const SCHEDULE_REFRESH_MIN: i64 = 10;
+
+/// Hour of the night the MBTA's service day changes at.  Trains running after midnight belong to
+/// the previous day's service, with times past 24:00
+pub const SERVICE_DAY_START_HOUR: u32 = 3;
+
+/// Number of trains of the next service day retrieved once today's service is over
+const FIRST_TRAINS: usize = 2;
+
+/// The service day that is running at now, in the agency's time zone
+pub fn service_date(now: DateTime<Tz>) -> NaiveDate {
+    let today = now.date_naive();
+    if now.hour() < SERVICE_DAY_START_HOUR {
+        today.pred_opt().unwrap_or(today)
+    } else {
+        today
+    }
+}
+
+/// Main function to retrieve train departures for the query, keeping the trips its filter lets
+/// through.  If only one of the predictions and schedules requests fails the departures are
+/// merged from the other one, with the timetable, if there is one, standing in for failed
+/// schedules.  The destination's schedules are kept in destination_schedules between calls.
+/// Times are given in the agency's time zone
+pub fn train_times(
+    client: &MbtaClient,
+    query: &DepartureQuery,
+    timetable: Option<&GtfsFeed>,
+    destination_schedules: &mut DestinationSchedules,
+) -> Result<MergedDepartures, Box<dyn std::error::Error>> {
+    let now = agency_time::now(query.time_zone);
+    let (station, dir_code, routes) = (&query.station, &query.dir_code, &query.routes);
+    // get prediction times
+    let predicted =
+        get_prediction_times(client, station, dir_code, routes, query.event, query.time_zone);
+    // get schuduled times
+    let mut scheduled =
+        get_scheduled_times(client, station, dir_code, routes, query.event, query.time_zone);
+    // the timetable stands in for the schedules, so predictions still have trains to override
+    // and trains without a prediction still show
+    if let (Err(err), Some(timetable)) = (&scheduled, timetable) {
+        warn!("could not retrieve schedules, using the GTFS timetable: {}", err);
+        scheduled = timetable_departures(timetable, query, now);
+    }
+    let mut merged = MergedDepartures::merge(predicted, scheduled, now)?;
+    let destination_stops = destination_stops(
+        client,
+        &query.filter,
+        dir_code,
+        routes,
+        query.time_zone,
+        destination_schedules,
+    );
+    merged.departures = query.filter.apply(merged.departures, destination_stops.as_ref());
+    Ok(merged)
+}
+
+/// The upcoming departures in the timetable for the query by trip, keeping the earlier of two
+/// service days' runs of a trip
+pub fn timetable_departures(
+    timetable: &GtfsFeed,
+    query: &DepartureQuery,
+    now: DateTime<Tz>,
+) -> Result<TripDepartures, Box<dyn std::error::Error>> {
+    let direction_id = query.dir_code.parse::<u8>()?;
+    let mut departures = TripDepartures::new();
+    for departure in timetable
+        .upcoming_departures(&query.routes, direction_id, query.event, now)
+        .unwrap_or_default()
+    {
+        departures.entry(departure.trip_id.clone()).or_insert(departure);
+    }
+    Ok(departures)
+}
+
+/// Retrieves the trips calling at the filter's destination, None if it has none.  If they can not
+/// be retrieved the destination is not filtered on, so the board still shows trains
+pub fn destination_stops(
+    client: &MbtaClient,
+    filter: &TripFilter,
+    dir_code: &str,
+    routes: &[String],
+    time_zone: Tz,
+    schedules: &mut DestinationSchedules,
+) -> Option<DestinationStops> {
+    let destination = filter.destination.as_ref()?;
+    get_destination_stops(client, destination, dir_code, routes, time_zone, schedules)
+        .map_err(|err| {
+            warn!("could not retrieve the trips to {}, showing all: {}", destination, err)
         })
-        .collect::<Vec<DateTime<Local>>>();
-    all_times.sort();
-    //    println!("{:?}", all_times);
-    if all_times.len() == 0usize {
-        return Ok(None);
+        .ok()
+}
+
+/// The trips letting passengers off at a destination on a service day and the next, kept so that
+/// refreshes only retrieve the destination's predictions until the service day changes
+#[derive(Debug, Clone, Default)]
+pub struct DestinationSchedules {
+    // the destination, direction, routes and service day the stops were retrieved for
+    key: Option<(String, String, Vec<String>, NaiveDate)>,
+    stops: DestinationStops,
+}
+
+impl DestinationSchedules {
+    /// The scheduled stops at the destination on the current and next service days, retrieved
+    /// again only if the service day or the destination, direction or routes changed
+    pub fn stops(
+        &mut self,
+        client: &MbtaClient,
+        destination: &str,
+        dir_code: &str,
+        routes: &[String],
+        time_zone: Tz,
+    ) -> Result<&DestinationStops, Box<dyn std::error::Error>> {
+        let service_date = service_date(agency_time::now(time_zone));
+        let key = (
+            destination.to_string(),
+            dir_code.to_string(),
+            routes.to_vec(),
+            service_date,
+        );
+        if self.key.as_ref() != Some(&key) {
+            self.stops = get_destination_schedules(
+                client,
+                destination,
+                dir_code,
+                routes,
+                service_date,
+                time_zone,
+            )?;
+            self.key = Some(key);
+        }
+        Ok(&self.stops)
+    }
+}
+
+/// Retrieves the trips in the direction that let passengers off at the destination on the current
+/// and next service days, with their stop sequence and arrival time there.  The schedules come
+/// from the ones kept for the service day, predictions replace their arrivals and drop the trips
+/// that will no longer stop there
+pub fn get_destination_stops(
+    client: &MbtaClient,
+    destination: &str,
+    dir_code: &str,
+    routes: &[String],
+    time_zone: Tz,
+    schedules: &mut DestinationSchedules,
+) -> Result<DestinationStops, Box<dyn std::error::Error>> {
+    let mut stops = schedules
+        .stops(client, destination, dir_code, routes, time_zone)?
+        .clone();
+    let path = format!(
+        "/predictions?filter[stop]={}&filter[route]={}&filter[direction_id]={}",
+        destination,
+        routes.join(","),
+        dir_code
+    );
+    for prediction in client.get::<Prediction>(&path)?.data {
+        let trip_id = match prediction.related_id("trip") {
+            Some(trip_id) => trip_id,
+            None => continue,
+        };
+        let attributes = &prediction.attributes;
+        if matches!(
+            attributes.schedule_relationship.as_deref(),
+            Some("CANCELLED") | Some("SKIPPED")
+        ) {
+            stops.remove(trip_id);
+            continue;
+        }
+        let arrival = TimeEvent::Arrival.pick(attributes.arrival_time, attributes.departure_time);
+        if let (Some(stop), Some(arrival)) = (stops.get_mut(trip_id), arrival) {
+            stop.arrival = Some(arrival.with_timezone(&time_zone));
+        }
+    }
+    Ok(stops)
+}
+
+/// Retrieves the trips in the direction that let passengers off at the destination on the service
+/// day and the next, with their stop sequence and scheduled arrival time there
+pub fn get_destination_schedules(
+    client: &MbtaClient,
+    destination: &str,
+    dir_code: &str,
+    routes: &[String],
+    service_date: NaiveDate,
+    time_zone: Tz,
+) -> Result<DestinationStops, Box<dyn std::error::Error>> {
+    let mut stops = DestinationStops::new();
+    for date in [Some(service_date), service_date.succ_opt()].iter().flatten() {
+        let path = format!(
+            "/schedules?filter[stop]={}&filter[date]={}&filter[route]={}&filter[direction_id]={}",
+            destination,
+            date.format("%Y-%m-%d"),
+            routes.join(","),
+            dir_code
+        );
+        for schedule in client.get::<Schedule>(&path)?.data {
+            // drop off type 1 means passengers can not get off there
+            if schedule.attributes.drop_off_type == Some(1) {
+                continue;
+            }
+            let attributes = &schedule.attributes;
+            if let (Some(trip_id), Some(stop_sequence)) =
+                (schedule.related_id("trip"), attributes.stop_sequence)
+            {
+                let arrival = TimeEvent::Arrival
+                    .pick(attributes.arrival_time, attributes.departure_time)
+                    .map(|arrival| arrival.with_timezone(&time_zone));
+                stops.insert(
+                    trip_id.to_string(),
+                    DestinationStop {
+                        stop_sequence,
+                        arrival,
+                    },
+                );
+            }
+        }
+    }
+    Ok(stops)
+}
+
+/// What a prediction stream reports whenever it sends something
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamUpdate {
+    /// The departures changed to these
+    Departures(Option<Vec<Departure>>),
+    /// The stream is still open but the departures did not change, e.g. after a keep alive
+    Alive,
+}
+
+/// Streams the predictions for a station, calling on_update with the merged departures whenever
+/// a prediction changes and with Alive for anything else the MBTA sends.  Returns when the MBTA
+/// closes the stream, and fails if it stays silent for too long
+pub fn stream_train_times(
+    client: &MbtaClient,
+    dir_code: &str,
+    station: &str,
+    routes: &[String],
+    event: TimeEvent,
+    time_zone: Tz,
+    on_update: &mut dyn FnMut(StreamUpdate),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut scheduled = get_scheduled_times(client, station, dir_code, routes, event, time_zone)?;
+    let mut scheduled_at = agency_time::now(time_zone);
+    let events = EventReader::new(client.stream(&predictions_path(station, dir_code, routes))?);
+    let mut predictions = PredictionSet::default();
+    for server_event in events {
+        if !predictions.apply(&server_event?)? {
+            on_update(StreamUpdate::Alive);
+            continue;
+        }
+        // schedules rarely change, but min_time moves on, so refetch them now and then.  The
+        // predictions are still good if that fails, so keep the old schedules until the next try
+        let now = agency_time::now(time_zone);
+        if now.signed_duration_since(scheduled_at) > Duration::minutes(SCHEDULE_REFRESH_MIN) {
+            match get_scheduled_times(client, station, dir_code, routes, event, time_zone) {
+                Ok(new_scheduled) => scheduled = new_scheduled,
+                Err(err) => warn!("could not refresh schedules, keeping the old ones: {}", err),
+            }
+            scheduled_at = now;
+        }
+        let predicted = prediction_departures(&predictions.document()?, event, time_zone)?;
+        on_update(StreamUpdate::Departures(merge_departures(predicted, scheduled.clone(), now)));
+    }
+    Ok(())
+}
+
+/// Replaces the scheduled departures with the more accurate predicted ones and flags the
+/// cancelled ones, then returns the departures after now sorted by time.  Returns None if there
+/// are no departures left
+pub fn merge_departures(
+    predicted: Predictions,
+    mut departures: TripDepartures,
+    now: DateTime<Tz>,
+) -> Option<Vec<Departure>> {
+    // cancelled trips keep their scheduled time, so the board can show which train is not coming
+    for trip_id in &predicted.cancelled {
+        if let Some(scheduled) = departures.get_mut(trip_id) {
+            scheduled.cancelled = true;
+        }
+    }
+    // keep the headsign from the schedule if the prediction did not have one
+    for (trip_id, mut prediction) in predicted.departures {
+        if predicted.cancelled.contains(&trip_id) {
+            continue;
+        }
+        if let Some(scheduled) = departures.get(&trip_id) {
+            if prediction.headsign.is_none() {
+                prediction.headsign = scheduled.headsign.clone();
+            }
+            prediction.next_service_day = scheduled.next_service_day;
+        }
+        departures.insert(trip_id, prediction);
     }
-    return Ok(Some(all_times));
+    // filter out any train time before now
+    let mut all_departures = departures
+        .into_values()
+        .filter(|departure| departure.time > now)
+        .collect::<Vec<Departure>>();
+    all_departures.sort_by_key(|departure| departure.time);
+    if all_departures.is_empty() {
+        return None;
+    }
+    Some(all_departures)
+}
+
+/// Retreives the ids of all routes that serve the station
+pub fn station_routes(
+    client: &MbtaClient,
+    station: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // MBTA API for the routes serving a stop
+    let path = format!("/routes?filter[stop]={}", station);
+    let routes = client.get::<Route>(&path)?;
+    Ok(routes.data.into_iter().map(|route| route.id).collect())
+}
+
+/// Retrieves the directions of a route from its route resource
+pub fn get_route_directions(
+    client: &MbtaClient,
+    route: &str,
+) -> Result<Vec<Direction>, Box<dyn std::error::Error>> {
+    let routes = client.get::<Route>(&format!("/routes?filter[id]={}", route))?;
+    let route = routes
+        .data
+        .first()
+        .ok_or_else(|| format!("unknown route {}", route))?;
+    Ok(route_directions(&route.attributes))
+}
+
+/// Number of the next predicted trains whose vehicles are looked up
+const VEHICLE_TRAINS: usize = 2;
+
+/// Retrieves where the trains running the next predicted departures are, with how many stops
+/// they are from the station and how full they are.  The stops are counted from the trips'
+/// schedules, as stop sequences can skip numbers
+pub fn get_vehicle_statuses(
+    client: &MbtaClient,
+    departures: &[Departure],
+) -> Result<VehicleStatuses, Box<dyn std::error::Error>> {
+    let trips: Vec<&Departure> = departures
+        .iter()
+        .filter(|departure| departure.is_predicted() && !departure.cancelled)
+        .take(VEHICLE_TRAINS)
+        .collect();
+    let mut statuses = VehicleStatuses::new();
+    if trips.is_empty() {
+        return Ok(statuses);
+    }
+    let trip_ids = trips
+        .iter()
+        .map(|departure| departure.trip_id.as_str())
+        .collect::<Vec<&str>>()
+        .join(",");
+    let vehicles =
+        client.get::<Vehicle>(&format!("/vehicles?filter[trip]={}&include=stop", trip_ids))?;
+    let schedules = client.get::<Schedule>(&format!("/schedules?filter[trip]={}", trip_ids))?;
+    for vehicle in &vehicles.data {
+        let departure = match trips
+            .iter()
+            .find(|departure| vehicle.related_id("trip") == Some(departure.trip_id.as_str()))
+        {
+            Some(departure) => departure,
+            None => continue,
+        };
+        let current_status = match vehicle.attributes.current_status {
+            Some(current_status) => current_status,
+            None => continue,
+        };
+        let stop_name = vehicles
+            .related::<Stop>(vehicle, "stop")?
+            .map(|stop| stop.attributes.name);
+        let stops_away = match (vehicle.attributes.current_stop_sequence, departure.stop_sequence) {
+            (Some(current), Some(station)) if current <= station => {
+                let stops_away = schedules
+                    .data
+                    .iter()
+                    .filter(|schedule| schedule.related_id("trip") == vehicle.related_id("trip"))
+                    .filter_map(|schedule| schedule.attributes.stop_sequence)
+                    .filter(|stop_sequence| {
+                        // the stop a train is standing at is already reached
+                        let reached = current_status == VehicleStopStatus::StoppedAt;
+                        (*stop_sequence > current || (*stop_sequence == current && !reached))
+                            && *stop_sequence <= station
+                    })
+                    .count();
+                Some(stops_away as u32)
+            }
+            _ => None,
+        };
+        let occupancy = Occupancy {
+            status: vehicle.attributes.occupancy_status,
+            carriages: vehicle.attributes.carriages.clone(),
+        };
+        statuses.insert(
+            departure.trip_id.clone(),
+            VehicleStatus {
+                current_status,
+                stop_name,
+                stops_away,
+                occupancy: occupancy.percentage().map(|_| occupancy),
+            },
+        );
+    }
+    Ok(statuses)
+}
+
+/// Adds where their trains are to the departures, leaving the others without
+pub fn add_vehicles(
+    departures: Option<Vec<Departure>>,
+    statuses: &VehicleStatuses,
+) -> Option<Vec<Departure>> {
+    let mut departures = departures?;
+    for departure in departures.iter_mut() {
+        departure.vehicle = statuses.get(&departure.trip_id).cloned();
+    }
+    Some(departures)
 }
 
 /// Retreived MBTA predicted times with their API
-fn get_prediction_times(
+pub fn get_prediction_times(
+    client: &MbtaClient,
     station: &str,
     dir_code: &str,
-) -> Result<Option<HashMap<String, DateTime<Local>>>, Box<dyn std::error::Error>> {
-    // MBTA API for predicted times
-    let address = format!("https://api-v3.mbta.com/predictions?filter[stop]=place-{}&filter[direction_id]={}&include=stop&filter[route]=CR-Needham", station, dir_code);
-    return get_rout_times(address);
+    routes: &[String],
+    event: TimeEvent,
+    time_zone: Tz,
+) -> Result<Predictions, Box<dyn std::error::Error>> {
+    let predictions = client.get::<Prediction>(&predictions_path(station, dir_code, routes))?;
+    prediction_departures(&predictions, event, time_zone)
 }
 
-/// Retreived MBTA scheduled times with their API
-fn get_scheduled_times(
+/// MBTA API for predicted times
+fn predictions_path(station: &str, dir_code: &str, routes: &[String]) -> String {
+    format!("/predictions?filter[stop]={}&filter[direction_id]={}&include=stop,trip&filter[route]={}", station, dir_code, routes.join(","))
+}
+
+/// Converts a document of MBTA predictions into departures at the time of the event, in the
+/// agency's time zone.  Cancelled trips, and trips skipping the station, have no times and are
+/// only listed as cancelled
+pub fn prediction_departures(
+    predictions: &Document<Prediction>,
+    event: TimeEvent,
+    time_zone: Tz,
+) -> Result<Predictions, Box<dyn std::error::Error>> {
+    let mut departures = Predictions::default();
+    for prediction in &predictions.data {
+        let attributes = &prediction.attributes;
+        // added trips are not in the schedules, so for them the prediction is used as it is
+        if matches!(
+            attributes.schedule_relationship.as_deref(),
+            Some("CANCELLED") | Some("SKIPPED")
+        ) {
+            if let Some(trip_id) = prediction.related_id("trip") {
+                departures.cancelled.insert(trip_id.to_string());
+            }
+            continue;
+        }
+        if let Some(mut departure) = new_departure(
+            predictions,
+            prediction,
+            event.pick(attributes.arrival_time, attributes.departure_time),
+            TimeSource::Predicted,
+            time_zone,
+        )? {
+            departure.status = attributes.status.clone();
+            departure.stop_sequence = attributes.stop_sequence;
+            departure.direction_id = attributes.direction_id.unwrap_or(departure.direction_id);
+            departures
+                .departures
+                .insert(departure.trip_id.clone(), departure);
+        }
+    }
+    Ok(departures)
+}
+
+/// Retreived MBTA scheduled times with their API, for the rest of the current service day.  Once
+/// that is over the first trains of the next service day are retrieved instead
+pub fn get_scheduled_times(
+    client: &MbtaClient,
     station: &str,
     dir_code: &str,
-) -> Result<Option<HashMap<String, DateTime<Local>>>, Box<dyn std::error::Error>> {
-    let now = chrono::Local::now();
-    // MBTA API for scheduled times
-    let address = format!("https://api-v3.mbta.com/schedules?include=route,trip,stop&filter[min_time]={}%3A{}&filter[stop]=place-{}&filter[route]=CR-Needham&filter[direction_id]={}",now.hour(), now.minute(), station, dir_code);
-    return get_rout_times(address);
-}
-
-/// Retreives the JSON from MBTA API and parses it into a hasmap
-fn get_rout_times(
-    address: String,
-) -> Result<Option<HashMap<String, DateTime<Local>>>, Box<dyn std::error::Error>> {
-    // retrieve the routes with the MBTA API returning a converted JSON format
-    let routes_json: Value = reqwest::blocking::get(&address)?.json()?;
-    // only interested in the "data" field
-    let data_option = routes_json.get("data");
-    // if there is a "data" field, proceed
-    if let Some(data) = data_option {
-        // if the "data" field is an array, proceed
-        if let Some(data_array) = data.as_array() {
-            // create a new HashMap to put int trip_id and departure time
-            let mut commuter_rail_dep_time: HashMap<String, chrono::DateTime<Local>> =
-                HashMap::new();
-            // for each train in the data array, insert the trip_id and departure time
-            for train in data_array {
-                let departure_time_option = train["attributes"]["departure_time"].as_str();
-                let trip_id_option = train["relationships"]["trip"]["data"]["id"].as_str();
-                // if there is a trip id
-                if let Some(trip_id) = trip_id_option {
-                    // and if there is a departure time for the train
-                    if let Some(departure_time) = departure_time_option {
-                        // convert departure time to DateTime<Local>
-                        let departure_time_datetime =
-                            Local.datetime_from_str(departure_time, "%+")?;
-                        // insert into HashMap
-                        commuter_rail_dep_time.insert(trip_id.to_string(), departure_time_datetime);
-                    }
-                }
-            }
-            // if successful return the trip_id, departure time HashMap, else return None
-            return Ok(Some(commuter_rail_dep_time));
-        } else {
-            return Ok(None);
+    routes: &[String],
+    event: TimeEvent,
+    time_zone: Tz,
+) -> Result<TripDepartures, Box<dyn std::error::Error>> {
+    let now = agency_time::now(time_zone);
+    let service_date = service_date(now);
+    // the time into the service day, past 24:00 after midnight
+    let hours = now.date_naive().signed_duration_since(service_date).num_days() * 24
+        + i64::from(now.hour());
+    let path = format!(
+        "{}&filter[min_time]={:02}%3A{:02}",
+        schedules_path(service_date, station, dir_code, routes),
+        hours,
+        now.minute()
+    );
+    let departures = schedule_departures(&client.get::<Schedule>(&path)?, event, time_zone)?;
+    if !departures.is_empty() {
+        return Ok(departures);
+    }
+    let next_date = service_date
+        .succ_opt()
+        .ok_or("the calendar ran out of days")?;
+    let sort = match event {
+        TimeEvent::Departure => "departure_time",
+        TimeEvent::Arrival => "arrival_time",
+    };
+    let path = format!(
+        "{}&sort={}&page[limit]={}",
+        schedules_path(next_date, station, dir_code, routes),
+        sort,
+        FIRST_TRAINS
+    );
+    let mut departures = schedule_departures(&client.get::<Schedule>(&path)?, event, time_zone)?;
+    for departure in departures.values_mut() {
+        departure.next_service_day = true;
+    }
+    Ok(departures)
+}
+
+/// MBTA API for the scheduled times on a service day
+pub fn schedules_path(
+    service_date: NaiveDate,
+    station: &str,
+    dir_code: &str,
+    routes: &[String],
+) -> String {
+    format!("/schedules?filter[date]={}&include=route,trip,stop&filter[stop]={}&filter[route]={}&filter[direction_id]={}", service_date.format("%Y-%m-%d"), station, routes.join(","), dir_code)
+}
+
+/// Converts a document of MBTA schedules into departures at the time of the event, in the
+/// agency's time zone
+pub fn schedule_departures(
+    schedules: &Document<Schedule>,
+    event: TimeEvent,
+    time_zone: Tz,
+) -> Result<TripDepartures, Box<dyn std::error::Error>> {
+    let mut departures = TripDepartures::new();
+    for schedule in &schedules.data {
+        let attributes = &schedule.attributes;
+        if let Some(mut departure) = new_departure(
+            schedules,
+            schedule,
+            event.pick(attributes.arrival_time, attributes.departure_time),
+            TimeSource::Scheduled,
+            time_zone,
+        )? {
+            departure.stop_sequence = attributes.stop_sequence;
+            departure.direction_id = attributes.direction_id.unwrap_or(departure.direction_id);
+            departures.insert(departure.trip_id.clone(), departure);
         }
-    } else {
-        return Ok(None);
+    }
+    Ok(departures)
+}
+
+/// Creates a departure from a prediction or schedule resource with its related trip.  Returns
+/// None if the resource has no trip or no time
+fn new_departure<A: ResourceType>(
+    document: &Document<A>,
+    resource: &Resource<A>,
+    time: Option<DateTime<FixedOffset>>,
+    source: TimeSource,
+    time_zone: Tz,
+) -> Result<Option<Departure>, Box<dyn std::error::Error>> {
+    let (trip_id, time) = match (resource.related_id("trip"), time) {
+        (Some(trip_id), Some(time)) => (trip_id.to_string(), time.with_timezone(&time_zone)),
+        _ => return Ok(None),
     };
+    let trip = document.related::<Trip>(resource, "trip")?;
+    let direction_id = trip
+        .as_ref()
+        .and_then(|trip| trip.attributes.direction_id)
+        .unwrap_or_default();
+    Ok(Some(Departure {
+        trip_id,
+        route_id: resource.related_id("route").unwrap_or_default().to_string(),
+        headsign: trip.and_then(|trip| trip.attributes.headsign),
+        direction_id,
+        time,
+        source,
+        status: None,
+        stop_sequence: None,
+        cancelled: false,
+        next_service_day: false,
+        destination_time: None,
+        vehicle: None,
+    }))
 }
@@ -0,0 +1,60 @@
+mod common;
+
+use chrono_tz::America::New_York;
+use common::{needham_inbound, recorded, Reply, StandIn};
+use forest_hills_departure::agency_time;
+use forest_hills_departure::alerts::{banner_text, service_alerts, DEFAULT_MIN_SEVERITY};
+use forest_hills_departure::mbta_api::MbtaClient;
+
+#[test]
+fn only_severe_alerts_in_effect_are_shown() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![Reply::json(
+        "/alerts",
+        recorded("forest_hills_inbound/alerts.json", now),
+    )]);
+    let query = needham_inbound();
+    let alerts = service_alerts(
+        &MbtaClient::new(&stand_in.url),
+        &query.station,
+        &query.routes,
+        DEFAULT_MIN_SEVERITY,
+    )
+    .unwrap();
+    // the elevator is below the threshold and the cancelled 600 ran before now, while the
+    // schedule change is at the threshold and has no end.  The most severe comes first
+    let banners: Vec<String> = alerts.iter().map(banner_text).collect();
+    assert_eq!(
+        banners,
+        vec![
+            "Needham Line Train 608 is 15-20 min late",
+            "Needham Line trains follow a new schedule",
+        ]
+    );
+    assert_eq!(
+        stand_in.requests(),
+        vec!["/alerts?filter[stop]=place-forhl&filter[route]=CR-Needham"]
+    );
+}
+
+#[test]
+fn the_header_is_shown_without_a_short_header() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![Reply::json(
+        "/alerts",
+        recorded("forest_hills_inbound/alerts.json", now),
+    )]);
+    let query = needham_inbound();
+    let alerts = service_alerts(&MbtaClient::new(&stand_in.url), &query.station, &query.routes, 0)
+        .unwrap();
+    // every alert in effect, with the lower threshold the elevator too
+    assert_eq!(alerts.len(), 3);
+    let schedule_change = alerts
+        .iter()
+        .find(|alert| alert.effect == "SCHEDULE_CHANGE")
+        .unwrap();
+    assert_eq!(schedule_change.short_header.as_deref(), Some(""));
+    assert_eq!(banner_text(schedule_change), schedule_change.header);
+    let elevator = alerts.iter().find(|alert| alert.effect == "STATION_ISSUE").unwrap();
+    assert_eq!(banner_text(elevator), "Forest Hills elevator serviced");
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use chrono::Duration;
+use chrono_tz::America::New_York;
+use common::needham_inbound;
+use forest_hills_departure::agency_time;
+use forest_hills_departure::departure_cache::CachedDepartures;
+use forest_hills_departure::train_time::{Departure, DepartureQuery, TimeSource};
+use std::path::PathBuf;
+
+fn departure(trip_id: &str, in_minutes: i64) -> Departure {
+    Departure {
+        trip_id: trip_id.to_string(),
+        route_id: "CR-Needham".to_string(),
+        headsign: Some("South Station".to_string()),
+        direction_id: 1,
+        time: agency_time::now(New_York) + Duration::minutes(in_minutes),
+        source: TimeSource::Predicted,
+        status: Some("On time".to_string()),
+        stop_sequence: Some(9),
+        cancelled: false,
+        next_service_day: false,
+        destination_time: None,
+        vehicle: None,
+    }
+}
+
+fn cache_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()))
+}
+
+#[test]
+fn cached_departures_survive_a_restart_without_past_trains() {
+    let path = cache_path("cache_restart");
+    let fetched_at = agency_time::now(New_York) - Duration::minutes(30);
+    let mut train_608 = departure("CR-608", 10);
+    train_608.destination_time = Some(train_608.time + Duration::minutes(17));
+    CachedDepartures {
+        query: needham_inbound(),
+        fetched_at,
+        departures: Some(vec![departure("CR-606", -5), train_608.clone()]),
+    }
+    .save(&path)
+    .unwrap();
+    let cached = CachedDepartures::load(
+        &path,
+        &needham_inbound(),
+        Duration::minutes(60),
+        agency_time::now(New_York),
+    )
+    .unwrap()
+    .unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(cached.fetched_at, fetched_at);
+    let departures = cached.departures.unwrap();
+    assert_eq!(departures.len(), 1);
+    assert_eq!(departures[0].trip_id, "CR-608");
+    assert_eq!(departures[0].status.as_deref(), Some("On time"));
+    assert_eq!(departures[0], train_608);
+}
+
+#[test]
+fn old_cache_is_discarded() {
+    let path = cache_path("cache_old");
+    CachedDepartures {
+        query: needham_inbound(),
+        fetched_at: agency_time::now(New_York) - Duration::minutes(90),
+        departures: Some(vec![departure("CR-608", 10)]),
+    }
+    .save(&path)
+    .unwrap();
+    let cached = CachedDepartures::load(
+        &path,
+        &needham_inbound(),
+        Duration::minutes(60),
+        agency_time::now(New_York),
+    )
+    .unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert!(cached.is_none());
+}
+
+#[test]
+fn cache_of_another_query_is_discarded() {
+    let path = cache_path("cache_other_query");
+    CachedDepartures {
+        query: needham_inbound(),
+        fetched_at: agency_time::now(New_York) - Duration::minutes(5),
+        departures: Some(vec![departure("CR-608", 10)]),
+    }
+    .save(&path)
+    .unwrap();
+    let outbound = DepartureQuery {
+        dir_code: "0".to_string(),
+        ..needham_inbound()
+    };
+    let cached =
+        CachedDepartures::load(&path, &outbound, Duration::minutes(60), agency_time::now(New_York))
+            .unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert!(cached.is_none());
+}
+
+#[test]
+fn missing_cache_is_not_an_error() {
+    let cached = CachedDepartures::load(
+        &cache_path("cache_missing"),
+        &needham_inbound(),
+        Duration::minutes(60),
+        agency_time::now(New_York),
+    )
+    .unwrap();
+    assert!(cached.is_none());
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{fixture, Reply, StandIn};
+use forest_hills_departure::departure_source::{DepartureSource, FixtureSource};
+use forest_hills_departure::mbta_api::{MbtaClient, Route};
+use forest_hills_departure::train_time::{get_route_directions, route_directions, Direction};
+use std::path::PathBuf;
+
+#[test]
+fn directions_come_from_the_route() {
+    let stand_in = StandIn::serve(vec![Reply::json(
+        "/routes?filter[id]=Orange",
+        fixture("directions/orange.json"),
+    )]);
+    let directions = get_route_directions(&MbtaClient::new(&stand_in.url), "Orange").unwrap();
+    assert_eq!(
+        directions,
+        vec![
+            Direction {
+                id: 0,
+                name: "South".to_string(),
+                destination: "Forest Hills".to_string(),
+            },
+            Direction {
+                id: 1,
+                name: "North".to_string(),
+                destination: "Oak Grove".to_string(),
+            },
+        ]
+    );
+    // picked by name or destination in any case, or by id
+    for input in ["north", "Oak_Grove", "Oak Grove", "1"].iter() {
+        assert!(directions[1].matches(input));
+        assert!(!directions[0].matches(input));
+    }
+    // the Orange Line has no inbound direction
+    assert!(!directions.iter().any(|direction| direction.matches("inbound")));
+}
+
+#[test]
+fn directions_the_route_does_not_run_in_are_left_out() {
+    let route = Route {
+        long_name: "Forest Hills - Back Bay Station".to_string(),
+        short_name: "39".to_string(),
+        route_type: 3,
+        direction_names: vec![None, Some("Inbound".to_string())],
+        direction_destinations: vec![None, Some("Back Bay Station".to_string())],
+        color: None,
+        description: None,
+    };
+    let directions = route_directions(&route);
+    assert_eq!(directions.len(), 1);
+    assert_eq!(directions[0].id, 1);
+}
+
+#[test]
+fn recorded_schedules_give_the_directions() {
+    let fixture_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join("forest_hills_inbound");
+    let mut source = FixtureSource::load(&fixture_dir).unwrap();
+    let directions = source.route_directions("CR-Needham").unwrap();
+    let inbound = directions
+        .iter()
+        .find(|direction| direction.matches("South_Station"))
+        .unwrap();
+    assert_eq!(inbound.id, 1);
+    assert_eq!(inbound.name, "Inbound");
+    assert!(source.route_directions("Orange").is_err());
+}
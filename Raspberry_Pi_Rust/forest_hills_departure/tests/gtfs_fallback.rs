@@ -0,0 +1,37 @@
+mod common;
+
+use chrono_tz::America::New_York;
+use common::{needham_inbound, write_gtfs_feed, Reply, StandIn};
+use forest_hills_departure::agency_time;
+use forest_hills_departure::departure_source::{DepartureSource, MbtaSource};
+use forest_hills_departure::gtfs_static::GtfsFeed;
+use forest_hills_departure::mbta_api::MbtaClient;
+use forest_hills_departure::train_time::TimeSource;
+
+#[test]
+fn timetable_is_used_when_the_api_fails() {
+    let feed_path = write_gtfs_feed("timetable_fallback", &[("CR-608", 20)]);
+    let timetable = GtfsFeed::load(&feed_path, "place-forhl").unwrap();
+    let stand_in = StandIn::serve(vec![Reply::status("/", 503)]);
+    let mut source = MbtaSource::new(MbtaClient::new(&stand_in.url)).with_timetable(timetable);
+    let departures = source.departures(&needham_inbound()).unwrap().departures.unwrap();
+    assert_eq!(departures.len(), 1);
+    assert_eq!(departures[0].trip_id, "CR-608");
+    assert_eq!(departures[0].headsign.as_deref(), Some("South Station"));
+    assert_eq!(departures[0].source, TimeSource::Scheduled);
+    // the trip runs on the current service day, also after midnight
+    assert!(!departures[0].next_service_day);
+    let minutes_away = departures[0]
+        .time
+        .signed_duration_since(agency_time::now(New_York))
+        .num_minutes();
+    assert!((18..=20).contains(&minutes_away));
+    std::fs::remove_file(feed_path).unwrap();
+}
+
+#[test]
+fn timetable_without_the_station_is_an_error() {
+    let feed_path = write_gtfs_feed("timetable_missing_station", &[("CR-608", 20)]);
+    assert!(GtfsFeed::load(&feed_path, "place-sstat").is_err());
+    std::fs::remove_file(feed_path).unwrap();
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use chrono_tz::America::New_York;
+use common::{needham_inbound, write_gtfs_feed, Reply, StandIn};
+use forest_hills_departure::agency_time;
+use forest_hills_departure::departure_source::{DepartureSource, GtfsRealtimeSource};
+use forest_hills_departure::gtfs_realtime::{
+    FeedEntity, FeedHeader, FeedMessage, StopRelationship, StopTimeEvent, StopTimeUpdate,
+    TripDescriptor, TripRelationship, TripUpdate,
+};
+use forest_hills_departure::gtfs_static::GtfsFeed;
+use forest_hills_departure::train_time::{Departure, TimeSource};
+use prost::Message;
+
+fn trip_update(trip_id: &str, stop_time_update: Vec<StopTimeUpdate>) -> FeedEntity {
+    FeedEntity {
+        id: trip_id.to_string(),
+        is_deleted: None,
+        trip_update: Some(TripUpdate {
+            trip: TripDescriptor {
+                trip_id: Some(trip_id.to_string()),
+                ..Default::default()
+            },
+            stop_time_update,
+            timestamp: None,
+            delay: None,
+        }),
+    }
+}
+
+fn departure_delay(stop_sequence: u32, delay: i32) -> StopTimeUpdate {
+    StopTimeUpdate {
+        stop_sequence: Some(stop_sequence),
+        departure: Some(StopTimeEvent {
+            delay: Some(delay),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// A feed where the 604 is cancelled, the 606 is five minutes late at Forest Hills, the 608 skips
+/// Forest Hills and the 610 is two minutes late at an earlier stop
+fn trip_updates_feed() -> Vec<u8> {
+    let mut cancelled = trip_update("CR-604", Vec::new());
+    if let Some(update) = cancelled.trip_update.as_mut() {
+        update
+            .trip
+            .set_schedule_relationship(TripRelationship::Canceled);
+    }
+    let mut skipped_stop = StopTimeUpdate {
+        stop_id: Some("FH-0000-02".to_string()),
+        ..Default::default()
+    };
+    skipped_stop.set_schedule_relationship(StopRelationship::Skipped);
+    FeedMessage {
+        header: FeedHeader {
+            gtfs_realtime_version: "2.0".to_string(),
+            timestamp: Some(agency_time::now(New_York).timestamp() as u64),
+        },
+        entity: vec![
+            cancelled,
+            trip_update("CR-606", vec![departure_delay(9, 300)]),
+            trip_update("CR-608", vec![skipped_stop]),
+            trip_update("CR-610", vec![departure_delay(5, 120)]),
+        ],
+    }
+    .encode_to_vec()
+}
+
+fn timetable(name: &str) -> GtfsFeed {
+    let feed_path = write_gtfs_feed(
+        name,
+        &[("CR-604", 10), ("CR-606", 20), ("CR-608", 30), ("CR-610", 40)],
+    );
+    let timetable = GtfsFeed::load(&feed_path, "place-forhl").unwrap();
+    std::fs::remove_file(feed_path).unwrap();
+    timetable
+}
+
+fn minutes_away(departure: &Departure) -> i64 {
+    departure
+        .time
+        .signed_duration_since(agency_time::now(New_York))
+        .num_minutes()
+}
+
+#[test]
+fn trip_updates_from_a_file_change_the_timetable() {
+    let updates_path = std::env::temp_dir().join(format!("trip_updates-{}.pb", std::process::id()));
+    std::fs::write(&updates_path, trip_updates_feed()).unwrap();
+    let mut source = GtfsRealtimeSource::new(
+        timetable("realtime_file"),
+        updates_path.to_str().unwrap(),
+    );
+    let departures = source.departures(&needham_inbound()).unwrap().departures.unwrap();
+    std::fs::remove_file(updates_path).unwrap();
+    let trip_ids: Vec<&str> = departures
+        .iter()
+        .map(|departure| departure.trip_id.as_str())
+        .collect();
+    assert_eq!(trip_ids, vec!["CR-604", "CR-606", "CR-608", "CR-610"]);
+    // the cancelled 604 and the 608 skipping the station keep their timetabled times
+    for cancelled in [&departures[0], &departures[2]].iter() {
+        assert!(cancelled.cancelled);
+        assert_eq!(cancelled.source, TimeSource::Scheduled);
+    }
+    assert!((9..=10).contains(&minutes_away(&departures[0])));
+    // the 606 is late at the station itself
+    assert!(!departures[1].cancelled);
+    assert_eq!(departures[1].source, TimeSource::Predicted);
+    assert_eq!(departures[1].headsign.as_deref(), Some("South Station"));
+    assert!((23..=25).contains(&minutes_away(&departures[1])));
+    // the 610 is still late from the earlier stop
+    assert_eq!(departures[3].source, TimeSource::Predicted);
+    assert!((40..=42).contains(&minutes_away(&departures[3])));
+}
+
+#[test]
+fn trip_updates_are_fetched_from_a_url() {
+    let stand_in = StandIn::serve(vec![Reply::bytes("/TripUpdates.pb", trip_updates_feed())]);
+    let mut source = GtfsRealtimeSource::new(
+        timetable("realtime_url"),
+        &format!("{}/TripUpdates.pb", stand_in.url),
+    );
+    let departures = source.departures(&needham_inbound()).unwrap().departures.unwrap();
+    assert_eq!(departures.len(), 4);
+    assert_eq!(stand_in.requests(), vec!["/TripUpdates.pb".to_string()]);
+}
+
+#[test]
+fn timetable_is_used_when_trip_updates_fail() {
+    let stand_in = StandIn::serve(vec![Reply::status("/", 503)]);
+    let mut source = GtfsRealtimeSource::new(
+        timetable("realtime_unavailable"),
+        &format!("{}/TripUpdates.pb", stand_in.url),
+    );
+    let departures = source.departures(&needham_inbound()).unwrap().departures.unwrap();
+    assert_eq!(departures.len(), 4);
+    assert!(departures
+        .iter()
+        .all(|departure| departure.source == TimeSource::Scheduled));
+}
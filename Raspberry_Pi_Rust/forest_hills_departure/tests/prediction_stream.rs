@@ -0,0 +1,41 @@
+mod common;
+
+use chrono_tz::America::New_York;
+use common::{needham_inbound, recorded, shifted, Reply, StandIn};
+use forest_hills_departure::agency_time;
+use forest_hills_departure::departure_source::{DepartureSource, MbtaSource};
+use forest_hills_departure::mbta_api::MbtaClient;
+use forest_hills_departure::train_time::{Departure, StreamUpdate, TimeSource};
+
+#[test]
+fn stream_events_update_departures() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json(
+            "/predictions",
+            recorded("forest_hills_inbound/prediction_stream.txt", now),
+        ),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let mut changes: Vec<Vec<Departure>> = Vec::new();
+    let mut keep_alives = 0;
+    MbtaSource::new(MbtaClient::new(&stand_in.url))
+        .stream(&needham_inbound(), &mut |update| match update {
+            StreamUpdate::Departures(departures) => changes.push(departures.unwrap()),
+            StreamUpdate::Alive => keep_alives += 1,
+        })
+        .unwrap();
+    // one change each for the reset, the update and the remove
+    assert_eq!(changes.len(), 3);
+    // the keep alive changes nothing, but shows the stream is still open
+    assert_eq!(keep_alives, 1);
+    assert_eq!(changes[0][0].time, shifted("2021-06-14T08:17:00-04:00", now));
+    assert_eq!(changes[1][0].time, shifted("2021-06-14T08:20:00-04:00", now));
+    // once the prediction is removed the 608 is back to its scheduled time
+    assert_eq!(changes[2][0].time, shifted("2021-06-14T08:14:00-04:00", now));
+    assert_eq!(changes[2][0].source, TimeSource::Scheduled);
+    assert!(stand_in
+        .requests()
+        .iter()
+        .any(|path| path.starts_with("/predictions")));
+}
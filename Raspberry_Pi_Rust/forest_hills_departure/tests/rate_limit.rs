@@ -0,0 +1,59 @@
+mod common;
+
+use chrono::Utc;
+use common::{fixture, Reply, StandIn};
+use forest_hills_departure::mbta_api::{MbtaClient, RateLimited, Route};
+
+/// A 429 reply to requests for the Orange line, with a quota that resets straight away so the
+/// client only waits a second before retrying
+fn rate_limited() -> Reply {
+    Reply::status("/routes?filter[id]=Orange", 429)
+        .with_header("x-ratelimit-limit", "20")
+        .with_header("x-ratelimit-remaining", "0")
+        .with_header("x-ratelimit-reset", &Utc::now().timestamp().to_string())
+}
+
+#[test]
+fn rate_limited_requests_are_retried_once_the_quota_resets() {
+    let stand_in = StandIn::serve(vec![
+        rate_limited().once(),
+        Reply::json("/routes?filter[id]=Orange", fixture("directions/orange.json"))
+            .with_header("x-ratelimit-limit", "20")
+            .with_header("x-ratelimit-remaining", "19")
+            .with_header("x-ratelimit-reset", &Utc::now().timestamp().to_string()),
+    ]);
+    let client = MbtaClient::new(&stand_in.url);
+    let routes = client.get::<Route>("/routes?filter[id]=Orange").unwrap();
+    assert_eq!(routes.data[0].id, "Orange");
+    assert_eq!(stand_in.requests().len(), 2);
+    // the quota of the successful response replaces the used up one
+    assert_eq!(client.rate_limit().unwrap().remaining, 19);
+}
+
+#[test]
+fn requests_still_rate_limited_after_the_retries_fail() {
+    let stand_in = StandIn::serve(vec![rate_limited()]);
+    let err = MbtaClient::new(&stand_in.url)
+        .get::<Route>("/routes?filter[id]=Orange")
+        .unwrap_err();
+    let refused = err.downcast_ref::<RateLimited>().unwrap();
+    assert_eq!(refused.rate_limit.unwrap().remaining, 0);
+    // the first attempt and three retries
+    assert_eq!(stand_in.requests().len(), 4);
+}
+
+#[test]
+fn the_api_key_is_sent_with_every_request() {
+    let stand_in = StandIn::serve(vec![Reply::json(
+        "/routes?filter[id]=Orange",
+        fixture("directions/orange.json"),
+    )]);
+    MbtaClient::new(&stand_in.url).get::<Route>("/routes?filter[id]=Orange").unwrap();
+    let client = MbtaClient::new(&stand_in.url).with_api_key("stand-in-key");
+    client.get::<Route>("/routes?filter[id]=Orange").unwrap();
+    client.get::<Route>("/routes?filter[id]=Orange").unwrap();
+    assert_eq!(
+        stand_in.request_headers("x-api-key"),
+        vec![None, Some("stand-in-key".to_string()), Some("stand-in-key".to_string())]
+    );
+}
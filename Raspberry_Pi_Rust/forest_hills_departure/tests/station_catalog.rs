@@ -0,0 +1,102 @@
+mod common;
+
+use common::{fixture, Reply, StandIn};
+use forest_hills_departure::mbta_api::MbtaClient;
+use forest_hills_departure::station_catalog::{StationCatalog, StopKind, STATION_ROUTE_TYPES};
+
+fn catalog_stand_in() -> StandIn {
+    StandIn::serve(vec![
+        Reply::json("/stops?filter[route_type]", fixture("stations/stops.json")),
+        Reply::json("/routes", fixture("stations/routes.json")),
+        Reply::json("/stops?filter[route]=Orange", fixture("stations/stops_orange.json")),
+        Reply::json("/stops?filter[route]=CR-Needham", fixture("stations/stops_needham.json")),
+        Reply::json("/stops?filter[route]=Boat-F1", fixture("stations/stops_hingham_hull.json")),
+    ])
+}
+
+#[test]
+fn platforms_are_grouped_into_their_station() {
+    let stand_in = catalog_stand_in();
+    let catalog = StationCatalog::fetch(&MbtaClient::new(&stand_in.url), &STATION_ROUTE_TYPES)
+        .unwrap();
+    let names: Vec<&str> = catalog
+        .stations
+        .iter()
+        .map(|station| station.name.as_str())
+        .collect();
+    assert_eq!(names, vec!["Forest Hills", "Long Wharf", "South Station"]);
+    let forest_hills = &catalog.stations[0];
+    assert_eq!(forest_hills.id, "place-forhl");
+    assert_eq!(forest_hills.latitude, Some(42.300523));
+    assert_eq!(forest_hills.kind, StopKind::ParentStation);
+    assert_eq!(forest_hills.routes, vec!["CR-Needham", "Orange"]);
+    assert_eq!(forest_hills.route_types, vec![1, 2]);
+    assert_eq!(catalog.stations[1].kind, StopKind::FerryPier);
+    assert_eq!(catalog.stations[1].routes, vec!["Boat-F1"]);
+    assert_eq!(catalog.stations[2].routes, vec!["CR-Needham"]);
+    assert!(stand_in.requests()[0].starts_with("/stops?filter[route_type]=0,1,2,4"));
+}
+
+#[test]
+fn stations_are_found_by_name_or_id() {
+    let stand_in = catalog_stand_in();
+    let catalog = StationCatalog::fetch(&MbtaClient::new(&stand_in.url), &STATION_ROUTE_TYPES)
+        .unwrap();
+    for name in ["Forest_Hills", "Forest Hills", "place-forhl", "forhl"].iter() {
+        assert_eq!(catalog.find(name).unwrap().id, "place-forhl");
+    }
+    assert_eq!(catalog.find("Long_Wharf").unwrap().id, "Boat-Long");
+    assert!(catalog.find("Forest").is_none());
+    assert_eq!(
+        catalog.argument_names(),
+        vec!["Forest_Hills", "Long_Wharf", "South_Station"]
+    );
+}
+
+#[test]
+fn bundled_catalog_needs_no_network() {
+    let catalog = StationCatalog::bundled().unwrap();
+    let forest_hills = catalog.find("Forest_Hills").unwrap();
+    assert_eq!(forest_hills.id, "place-forhl");
+    assert_eq!(forest_hills.routes, vec!["CR-Needham", "Orange"]);
+    assert_eq!(forest_hills.route_types, vec![1, 2]);
+}
+
+#[test]
+fn bus_stops_are_fetched_by_stop_id() {
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/stops?filter[id]=10642", fixture("stations/stop_10642.json")),
+        Reply::json("/routes?filter[stop]=10642", fixture("stations/routes_10642.json")),
+        Reply::json("/stops?filter[id]=", r#"{"data": [], "jsonapi": {"version": "1.0"}}"#.into()),
+    ]);
+    let client = MbtaClient::new(&stand_in.url);
+    let stop = StationCatalog::fetch_stop(&client, "10642").unwrap().unwrap();
+    assert_eq!(stop.id, "10642");
+    assert_eq!(stop.kind, StopKind::BusStop);
+    assert_eq!(stop.routes, vec!["39"]);
+    assert_eq!(stop.route_types, vec![3]);
+    // once added the bus stop is found offline like any station
+    let mut catalog = StationCatalog::bundled().unwrap();
+    assert!(catalog.find("10642").is_none());
+    catalog.insert(stop);
+    assert_eq!(catalog.find("10642").unwrap().name, "Centre St @ Myrtle St");
+    assert!(StationCatalog::fetch_stop(&client, "99999").unwrap().is_none());
+}
+
+#[test]
+fn refreshed_catalog_replaces_the_bundled_one() {
+    let path = std::env::temp_dir().join(format!("stations-{}.json", std::process::id()));
+    // without a refreshed catalog the bundled one is used
+    assert_eq!(
+        StationCatalog::local(&path).unwrap(),
+        StationCatalog::bundled().unwrap()
+    );
+    let stand_in = catalog_stand_in();
+    let catalog = StationCatalog::fetch(&MbtaClient::new(&stand_in.url), &STATION_ROUTE_TYPES)
+        .unwrap();
+    catalog.save(&path).unwrap();
+    let local = StationCatalog::local(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(local, catalog);
+    assert!(local.find("Long_Wharf").is_some());
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use chrono::{DateTime, Duration, NaiveDate, TimeZone};
+use chrono_tz::America::{Chicago, New_York};
+use common::{needham_inbound, RECORDED_AT};
+use forest_hills_departure::departure_cache::CachedDepartures;
+use forest_hills_departure::departure_source::{DepartureSource, FixtureSource};
+use forest_hills_departure::gtfs_static::service_time;
+use forest_hills_departure::train_time::{service_date, DepartureQuery};
+use std::path::PathBuf;
+
+fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap()
+}
+
+#[test]
+fn gtfs_times_after_clocks_go_forward_keep_their_clock_time() {
+    // clocks went forward at 2am on 14 March 2021.  GTFS counts from noon minus 12 hours, an hour
+    // before midnight that day, so only times before the change are an hour early
+    let before = service_time(date(2021, 3, 14), "01:30:00", New_York).unwrap();
+    let after = service_time(date(2021, 3, 14), "08:00:00", New_York).unwrap();
+    assert_eq!(before.to_rfc3339(), "2021-03-14T00:30:00-05:00");
+    assert_eq!(after.to_rfc3339(), "2021-03-14T08:00:00-04:00");
+}
+
+#[test]
+fn gtfs_times_past_midnight_count_through_the_repeated_hour() {
+    // clocks went back at 2am on 7 November 2021, so 01:30 came round twice
+    let first = service_time(date(2021, 11, 6), "25:30:00", New_York).unwrap();
+    let second = service_time(date(2021, 11, 6), "26:30:00", New_York).unwrap();
+    assert_eq!(first.to_rfc3339(), "2021-11-07T01:30:00-04:00");
+    assert_eq!(second.to_rfc3339(), "2021-11-07T01:30:00-05:00");
+    assert_eq!(second.signed_duration_since(first), Duration::hours(1));
+}
+
+#[test]
+fn service_day_changes_after_the_repeated_hour() {
+    let repeated = New_York
+        .with_ymd_and_hms(2021, 11, 7, 1, 30, 0)
+        .latest()
+        .unwrap();
+    let morning = New_York.with_ymd_and_hms(2021, 11, 7, 3, 0, 0).unwrap();
+    assert_eq!(service_date(repeated), date(2021, 11, 6));
+    assert_eq!(service_date(morning), date(2021, 11, 7));
+}
+
+#[test]
+fn api_times_are_given_in_the_agency_time_zone() {
+    let fixture_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join("forest_hills_inbound");
+    let recorded_at = DateTime::parse_from_rfc3339(RECORDED_AT).unwrap();
+    let mut source = FixtureSource::load(&fixture_dir)
+        .unwrap()
+        .with_now(recorded_at.with_timezone(&Chicago));
+    let query = DepartureQuery {
+        time_zone: Chicago,
+        ..needham_inbound()
+    };
+    let departures = source.departures(&query).unwrap().departures.unwrap();
+    // the delayed 608 leaves Forest Hills at 08:17 Boston time
+    assert_eq!(departures[0].time.timezone(), Chicago);
+    assert_eq!(departures[0].time.format("%H:%M").to_string(), "07:17");
+}
+
+#[test]
+fn cached_times_keep_their_time_zone() {
+    let path = std::env::temp_dir().join(format!("cache_time_zone-{}.json", std::process::id()));
+    let fetched_at = Chicago.with_ymd_and_hms(2021, 6, 14, 7, 0, 0).unwrap();
+    let query = DepartureQuery {
+        time_zone: Chicago,
+        ..needham_inbound()
+    };
+    CachedDepartures {
+        query: query.clone(),
+        fetched_at,
+        departures: None,
+    }
+    .save(&path)
+    .unwrap();
+    let cached = CachedDepartures::load(&path, &query, Duration::minutes(60), fetched_at)
+        .unwrap()
+        .unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(cached.fetched_at, fetched_at);
+    assert_eq!(cached.fetched_at.timezone(), Chicago);
+}
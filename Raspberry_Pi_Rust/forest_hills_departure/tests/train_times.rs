@@ -0,0 +1,259 @@
+mod common;
+
+use chrono::TimeZone;
+use chrono_tz::America::New_York;
+use common::{needham_inbound, recorded, shifted, Reply, StandIn};
+use forest_hills_departure::agency_time;
+use forest_hills_departure::departure_source::{DepartureSource, MbtaSource};
+use forest_hills_departure::mbta_api::MbtaClient;
+use forest_hills_departure::train_time::{
+    self, DepartureQuery, DestinationSchedules, EndpointHealth, TimeEvent, TimeSource,
+};
+
+fn source(stand_in: &StandIn) -> MbtaSource {
+    MbtaSource::new(MbtaClient::new(&stand_in.url))
+}
+
+#[test]
+fn prediction_overrides_schedule() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let departures = source(&stand_in)
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures
+        .unwrap();
+    // the delayed 608 is predicted three minutes after its scheduled time
+    let train_608 = &departures[0];
+    assert_eq!(train_608.trip_id, "CR-Weekday-Spring-21-608");
+    assert_eq!(train_608.time, shifted("2021-06-14T08:17:00-04:00", now));
+    assert_eq!(train_608.source, TimeSource::Predicted);
+    assert_eq!(train_608.status.as_deref(), Some("Delayed"));
+    // the prediction has no headsign, so the one from the schedule is kept
+    assert_eq!(train_608.headsign.as_deref(), Some("South Station"));
+    // the 610 has no prediction and keeps its scheduled time
+    let train_610 = &departures[1];
+    assert_eq!(train_610.trip_id, "CR-Weekday-Spring-21-610");
+    assert_eq!(train_610.time, shifted("2021-06-14T09:05:00-04:00", now));
+    assert_eq!(train_610.source, TimeSource::Scheduled);
+}
+
+#[test]
+fn past_trains_are_filtered() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let departures = source(&stand_in)
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures
+        .unwrap();
+    // the 606 left ten minutes before the recording
+    assert_eq!(departures.len(), 2);
+    assert!(departures
+        .iter()
+        .all(|departure| departure.trip_id != "CR-Weekday-Spring-21-606"));
+    assert!(departures.windows(2).all(|pair| pair[0].time <= pair[1].time));
+}
+
+#[test]
+fn empty_responses_give_no_departures() {
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", common::fixture("empty.json")),
+        Reply::json("/schedules", common::fixture("empty.json")),
+    ]);
+    let departures = source(&stand_in).departures(&needham_inbound()).unwrap().departures;
+    assert!(departures.is_none());
+}
+
+#[test]
+fn requests_go_to_the_configured_api_url() {
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", common::fixture("empty.json")),
+        Reply::json("/schedules", common::fixture("empty.json")),
+    ]);
+    source(&stand_in).departures(&needham_inbound()).unwrap();
+    let requests = stand_in.requests();
+    // predictions, today's schedules and the next service day's schedules
+    assert_eq!(requests.len(), 3);
+    assert!(requests
+        .iter()
+        .all(|path| path.contains("filter[route]=CR-Needham")));
+}
+
+#[test]
+fn predictions_are_shown_when_schedules_fail() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::status("/schedules", 503),
+    ]);
+    let client = MbtaClient::new(&stand_in.url);
+    let merged = train_time::train_times(
+        &client,
+        &needham_inbound(),
+        None,
+        &mut DestinationSchedules::default(),
+    )
+    .unwrap();
+    assert_eq!(merged.predictions, EndpointHealth::Ok);
+    assert!(!merged.schedules.is_ok());
+    // only the predicted 608 is left, without the headsign the schedule would have given it
+    let departures = merged.departures.unwrap();
+    assert_eq!(departures.len(), 1);
+    assert_eq!(departures[0].trip_id, "CR-Weekday-Spring-21-608");
+    assert_eq!(departures[0].source, TimeSource::Predicted);
+    assert_eq!(departures[0].headsign, None);
+}
+
+#[test]
+fn schedules_are_shown_when_predictions_fail() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::status("/predictions", 503),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let merged = source(&stand_in).departures(&needham_inbound()).unwrap();
+    // the board is told the predictions are down
+    assert!(!merged.predictions.is_ok());
+    assert_eq!(merged.schedules, EndpointHealth::Ok);
+    let departures = merged.departures.unwrap();
+    assert_eq!(departures.len(), 2);
+    assert_eq!(departures[0].time, shifted("2021-06-14T08:14:00-04:00", now));
+    assert!(departures
+        .iter()
+        .all(|departure| departure.source == TimeSource::Scheduled));
+}
+
+#[test]
+fn both_requests_failing_is_an_error() {
+    let stand_in = StandIn::serve(vec![Reply::status("/", 503)]);
+    assert!(source(&stand_in).departures(&needham_inbound()).is_err());
+}
+
+#[test]
+fn cancelled_trips_keep_their_scheduled_time() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json(
+            "/predictions",
+            recorded("forest_hills_inbound/predictions_cancelled.json", now),
+        ),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let departures = source(&stand_in)
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures
+        .unwrap();
+    assert_eq!(departures.len(), 2);
+    assert!(!departures[0].cancelled);
+    // the cancelled 610 has no predicted time, so it is flagged at its scheduled time
+    let train_610 = &departures[1];
+    assert_eq!(train_610.trip_id, "CR-Weekday-Spring-21-610");
+    assert!(train_610.cancelled);
+    assert_eq!(train_610.time, shifted("2021-06-14T09:05:00-04:00", now));
+    assert_eq!(train_610.source, TimeSource::Scheduled);
+}
+
+#[test]
+fn arrival_mode_counts_down_to_arrivals() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let query = DepartureQuery {
+        event: TimeEvent::Arrival,
+        ..needham_inbound()
+    };
+    let departures = source(&stand_in).departures(&query).unwrap().departures.unwrap();
+    assert_eq!(departures[0].time, shifted("2021-06-14T08:16:00-04:00", now));
+    assert_eq!(departures[1].time, shifted("2021-06-14T09:04:00-04:00", now));
+}
+
+#[test]
+fn trains_without_a_departure_time_use_their_arrival() {
+    let now = agency_time::now(New_York);
+    // the 610 terminating at the station only has an arrival time
+    let schedules = common::fixture("forest_hills_inbound/schedules.json").replace(
+        "\"departure_time\": \"2021-06-14T09:05:00-04:00\"",
+        "\"departure_time\": null",
+    );
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", common::fixture("empty.json")),
+        Reply::json("/schedules", common::shift_to_now(&schedules, now)),
+    ]);
+    let departures = source(&stand_in)
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures
+        .unwrap();
+    assert_eq!(departures.len(), 2);
+    assert_eq!(departures[1].trip_id, "CR-Weekday-Spring-21-610");
+    assert_eq!(departures[1].time, shifted("2021-06-14T09:04:00-04:00", now));
+}
+
+#[test]
+fn trains_after_midnight_belong_to_the_previous_service_day() {
+    let after_midnight = New_York.with_ymd_and_hms(2021, 6, 15, 1, 30, 0).unwrap();
+    let morning = New_York.with_ymd_and_hms(2021, 6, 15, 5, 0, 0).unwrap();
+    assert_eq!(
+        train_time::service_date(after_midnight).to_string(),
+        "2021-06-14"
+    );
+    assert_eq!(train_time::service_date(morning).to_string(), "2021-06-15");
+}
+
+#[test]
+fn schedules_are_requested_for_the_service_day() {
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", common::fixture("empty.json")),
+        Reply::json("/schedules", common::fixture("empty.json")),
+    ]);
+    source(&stand_in).departures(&needham_inbound()).unwrap();
+    let service_date = train_time::service_date(agency_time::now(New_York));
+    let schedules: Vec<String> = stand_in
+        .requests()
+        .into_iter()
+        .filter(|path| path.starts_with("/schedules"))
+        .collect();
+    // the rest of today's service, then the first trains of the next service day
+    assert_eq!(schedules.len(), 2);
+    assert!(schedules[0].starts_with(&format!("/schedules?filter[date]={}", service_date)));
+    assert!(schedules[0].contains("filter[min_time]="));
+    assert!(schedules[1].starts_with(&format!(
+        "/schedules?filter[date]={}",
+        service_date.succ_opt().unwrap()
+    )));
+    assert!(schedules[1].contains("sort=departure_time"));
+}
+
+#[test]
+fn first_trains_of_the_next_service_day_are_shown_after_service_ends() {
+    let now = agency_time::now(New_York);
+    let next_date = train_time::service_date(now).succ_opt().unwrap();
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", common::fixture("empty.json")),
+        Reply::json(
+            &format!("/schedules?filter[date]={}", next_date),
+            recorded("forest_hills_inbound/schedules.json", now),
+        ),
+        Reply::json("/schedules", common::fixture("empty.json")),
+    ]);
+    let departures = source(&stand_in)
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures
+        .unwrap();
+    assert_eq!(departures.len(), 2);
+    assert_eq!(departures[0].time, shifted("2021-06-14T08:14:00-04:00", now));
+    assert!(departures
+        .iter()
+        .all(|departure| departure.next_service_day && departure.source == TimeSource::Scheduled));
+}
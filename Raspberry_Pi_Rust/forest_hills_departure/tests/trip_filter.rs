@@ -0,0 +1,116 @@
+mod common;
+
+use chrono_tz::America::New_York;
+use common::{needham_inbound, recorded, shifted, Reply, StandIn};
+use forest_hills_departure::agency_time;
+use forest_hills_departure::departure_source::{DepartureSource, MbtaSource};
+use forest_hills_departure::mbta_api::MbtaClient;
+use forest_hills_departure::train_time::{
+    Departure, DepartureQuery, DestinationStop, DestinationStops, TripFilter,
+};
+
+fn trip_ids(departures: &Option<Vec<Departure>>) -> Vec<&str> {
+    departures
+        .iter()
+        .flatten()
+        .map(|departure| departure.trip_id.as_str())
+        .collect()
+}
+
+#[test]
+fn only_trips_letting_passengers_off_at_the_destination_are_shown() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json(
+            "/schedules?filter[stop]=place-rugg",
+            recorded("forest_hills_inbound/schedules_ruggles.json", now),
+        ),
+        Reply::json(
+            "/predictions?filter[stop]=place-rugg",
+            recorded("forest_hills_inbound/predictions_ruggles.json", now),
+        ),
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let query = DepartureQuery {
+        filter: TripFilter {
+            destination: Some("place-rugg".to_string()),
+            ..TripFilter::default()
+        },
+        ..needham_inbound()
+    };
+    let mut source = MbtaSource::new(MbtaClient::new(&stand_in.url));
+    let departures = source.departures(&query).unwrap().departures;
+    // the 610 runs through Ruggles without stopping
+    assert_eq!(trip_ids(&departures), vec!["CR-Weekday-Spring-21-608"]);
+    // the delayed 608 is predicted into Ruggles three minutes late
+    assert_eq!(
+        departures.unwrap()[0].destination_time,
+        Some(shifted("2021-06-14T08:21:00-04:00", now))
+    );
+    // trips are looked up for the current and next service days, and kept for the next refresh,
+    // which only needs the predictions at the destination
+    source.departures(&query).unwrap();
+    let destination_requests = |prefix: &str| {
+        stand_in
+            .requests()
+            .into_iter()
+            .filter(|request| request.starts_with(prefix))
+            .count()
+    };
+    assert_eq!(destination_requests("/schedules?filter[stop]=place-rugg"), 2);
+    assert_eq!(destination_requests("/predictions?filter[stop]=place-rugg"), 2);
+}
+
+#[test]
+fn trips_calling_at_the_destination_before_the_station_are_left_out() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let departures = MbtaSource::new(MbtaClient::new(&stand_in.url))
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures;
+    // the trains stop at Forest Hills ninth, so a destination they call at fifth is behind them
+    let mut destination_stops = DestinationStops::new();
+    for (trip_id, stop_sequence) in [("608", 5), ("610", 11)].iter() {
+        destination_stops.insert(
+            format!("CR-Weekday-Spring-21-{}", trip_id),
+            DestinationStop {
+                stop_sequence: *stop_sequence,
+                arrival: None,
+            },
+        );
+    }
+    let filter = TripFilter {
+        destination: Some("place-NB-0064".to_string()),
+        ..TripFilter::default()
+    };
+    let filtered = filter.apply(departures, Some(&destination_stops));
+    assert_eq!(trip_ids(&filtered), vec!["CR-Weekday-Spring-21-610"]);
+}
+
+#[test]
+fn headsigns_are_included_and_excluded() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let mut source = MbtaSource::new(MbtaClient::new(&stand_in.url));
+    let departures = |source: &mut MbtaSource, include: &[&str], exclude: &[&str]| {
+        let filter = TripFilter {
+            destination: None,
+            include_headsigns: include.iter().map(|headsign| headsign.to_string()).collect(),
+            exclude_headsigns: exclude.iter().map(|headsign| headsign.to_string()).collect(),
+        };
+        source.departures(&DepartureQuery { filter, ..needham_inbound() }).unwrap().departures
+    };
+    // every trip is headed for South Station
+    assert_eq!(trip_ids(&departures(&mut source, &["south_station"], &[])).len(), 2);
+    assert_eq!(trip_ids(&departures(&mut source, &["Needham", "SOUTH"], &[])).len(), 2);
+    assert!(departures(&mut source, &["Needham"], &[]).is_none());
+    assert!(departures(&mut source, &[], &["South Station"]).is_none());
+}
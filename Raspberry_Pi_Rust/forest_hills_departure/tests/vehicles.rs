@@ -0,0 +1,122 @@
+mod common;
+
+use chrono_tz::America::New_York;
+use common::{needham_inbound, recorded, Reply, StandIn};
+use forest_hills_departure::agency_time;
+use forest_hills_departure::config::Config;
+use forest_hills_departure::departure_source::{self, DepartureSource, MbtaSource};
+use forest_hills_departure::mbta_api::{
+    Carriage, MbtaClient, OccupancyStatus, VehicleStopStatus,
+};
+use forest_hills_departure::train_time::{Occupancy, VehicleStatus};
+
+#[test]
+fn next_train_knows_how_many_stops_away_it_is() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/vehicles", recorded("forest_hills_inbound/vehicles.json", now)),
+        Reply::json(
+            "/schedules?filter[trip]",
+            recorded("forest_hills_inbound/schedules_608.json", now),
+        ),
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    let departures = MbtaSource::new(MbtaClient::new(&stand_in.url))
+        .with_vehicles()
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures
+        .unwrap();
+    // the 608 is on its way to Bellevue, then stops at Roslindale Village before Forest Hills
+    let vehicle = departures[0].vehicle.as_ref().unwrap();
+    assert_eq!(vehicle.current_status, VehicleStopStatus::InTransitTo);
+    assert_eq!(vehicle.stop_name.as_deref(), Some("Bellevue"));
+    assert_eq!(vehicle.stops_away, Some(3));
+    assert_eq!(vehicle.text().as_deref(), Some("3 stops away"));
+    // commuter rail trains only report the whole train
+    let occupancy = vehicle.occupancy.as_ref().unwrap();
+    assert_eq!(occupancy.status, Some(OccupancyStatus::FewSeatsAvailable));
+    assert_eq!(occupancy.percentage(), Some(50));
+    // the 610 has no prediction, so its vehicle is not looked up
+    assert!(departures[1].vehicle.is_none());
+    assert!(stand_in
+        .requests()
+        .contains(&"/vehicles?filter[trip]=CR-Weekday-Spring-21-608&include=stop".to_string()));
+}
+
+#[test]
+fn vehicles_are_only_looked_up_when_asked_for() {
+    let now = agency_time::now(New_York);
+    let stand_in = StandIn::serve(vec![
+        Reply::json("/predictions", recorded("forest_hills_inbound/predictions.json", now)),
+        Reply::json("/schedules", recorded("forest_hills_inbound/schedules.json", now)),
+    ]);
+    // vehicles take two more requests every refresh, so the config leaves them off by default
+    let config = Config {
+        api_url: Some(stand_in.url.clone()),
+        ..Config::default()
+    };
+    let departures = departure_source::from_config(&config, "place-forhl")
+        .unwrap()
+        .departures(&needham_inbound())
+        .unwrap()
+        .departures
+        .unwrap();
+    assert!(departures.iter().all(|departure| departure.vehicle.is_none()));
+    assert!(!stand_in
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("/vehicles")));
+}
+
+#[test]
+fn stopped_trains_are_at_their_stop() {
+    let vehicle = VehicleStatus {
+        current_status: VehicleStopStatus::StoppedAt,
+        stop_name: Some("Roslindale Village".to_string()),
+        stops_away: Some(1),
+        occupancy: None,
+    };
+    assert_eq!(vehicle.text().as_deref(), Some("at Roslindale Village"));
+    let vehicle = VehicleStatus {
+        current_status: VehicleStopStatus::IncomingAt,
+        stop_name: Some("Forest Hills".to_string()),
+        stops_away: Some(1),
+        occupancy: None,
+    };
+    assert_eq!(vehicle.text().as_deref(), Some("1 stop away"));
+}
+
+#[test]
+fn occupancy_comes_from_the_carriages_where_they_report_it() {
+    let carriage = |percentage: Option<u8>, status: OccupancyStatus| Carriage {
+        label: None,
+        occupancy_status: Some(status),
+        occupancy_percentage: percentage,
+    };
+    // an Orange Line train with a crowded front carriage
+    let occupancy = Occupancy {
+        status: Some(OccupancyStatus::ManySeatsAvailable),
+        carriages: vec![
+            carriage(Some(80), OccupancyStatus::StandingRoomOnly),
+            carriage(Some(30), OccupancyStatus::ManySeatsAvailable),
+            carriage(Some(10), OccupancyStatus::ManySeatsAvailable),
+        ],
+    };
+    assert_eq!(occupancy.percentage(), Some(40));
+    // without percentages the train's status is used, then the carriages' statuses
+    let occupancy = Occupancy {
+        status: None,
+        carriages: vec![
+            carriage(None, OccupancyStatus::Full),
+            carriage(None, OccupancyStatus::FewSeatsAvailable),
+        ],
+    };
+    assert_eq!(occupancy.percentage(), Some(75));
+    let occupancy = Occupancy {
+        status: Some(OccupancyStatus::NoDataAvailable),
+        carriages: Vec::new(),
+    };
+    assert_eq!(occupancy.percentage(), None);
+}